@@ -0,0 +1,164 @@
+use super::client::Client;
+use super::media::MediaType;
+use crate::utils::write_atomic;
+use serde_derive::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ManifestEntry {
+    name: String,
+    attachment_id: String,
+}
+
+// Attachment ids of the uploaded assets, keyed by the sha256 of the file
+pub struct AssetManifest {
+    path: PathBuf,
+    entries: HashMap<String,ManifestEntry>,
+}
+
+impl AssetManifest {
+    // Load the manifest, a missing or broken file gives an empty one
+    pub fn load(path: &str) -> Self {
+        let entries = match fs::read_to_string(path) {
+            Ok(e) => serde_json::from_str(&e).unwrap_or_else(|e| {
+                warn!("Asset manifest {} is broken, every asset will be uploaded: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        AssetManifest{
+            path: PathBuf::from(path),
+            entries: entries,
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&self.entries)?;
+        write_atomic(&self.path, json.as_bytes())
+    }
+
+    pub fn get_attachment_id(&self, hash: &str) -> Option<&str> {
+        self.entries.get(hash).map(|x| x.attachment_id.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+// Upload every file of the directory not already in the manifest and give
+// the client the attachment id of each asset by its logical name
+pub fn upload_assets(dir: &str, manifest: &str, client: &Client) -> io::Result<usize> {
+    let mut files = Vec::new();
+    collect_files(Path::new(dir), &mut files)?;
+
+    let old = AssetManifest::load(manifest);
+    let mut new = AssetManifest{
+        path: PathBuf::from(manifest),
+        entries: HashMap::new(),
+    };
+    let mut uploaded = 0;
+
+    for file in files {
+        if file == Path::new(manifest) {
+            continue
+        }
+
+        let data = fs::read(&file)?;
+        let hash = hex::encode(Sha256::digest(&data));
+        let name = logical_name(Path::new(dir), &file);
+        let file_name = file.file_name().and_then(|x| x.to_str()).unwrap_or_default();
+        let (kind, _) = MediaType::from_file_name(file_name);
+
+        let id = match old.get_attachment_id(&hash) {
+            Some(e) => String::from(e),
+            None => match client.upload(&kind, file_name, &data) {
+                Ok(e) => {
+                    uploaded += 1;
+                    e
+                },
+                Err(e) => {
+                    warn!("Asset {} not uploaded: {}", name, e);
+                    continue
+                }
+            },
+        };
+
+        client.set_asset(&name, kind, &id);
+        new.entries.insert(hash, ManifestEntry{name: name, attachment_id: id});
+    }
+
+    new.save()?;
+    info!("{} assets ready, {} uploaded", new.len(), uploaded);
+    Ok(uploaded)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path.file_name().and_then(|x| x.to_str()).map(|x| x.starts_with('.')).unwrap_or(true);
+
+        if hidden {
+            continue
+        }
+        else if path.is_dir() {
+            collect_files(&path, files)?;
+        }
+        else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(())
+}
+
+// Path from the asset directory without extension, like "products/shoe"
+fn logical_name(dir: &Path, file: &Path) -> String {
+    let relative = file.strip_prefix(dir).unwrap_or(file).with_extension("");
+    relative.components()
+        .filter_map(|x| x.as_os_str().to_str())
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::upload_assets;
+    use crate::api::media::MediaType;
+    use crate::api::transport::recording_client;
+    use std::fs;
+
+    #[test]
+    fn upload_once() {
+        let dir = std::env::temp_dir().join(format!("botMessenger-assets-{}", std::process::id()));
+        fs::create_dir_all(dir.join("products")).unwrap();
+        fs::write(dir.join("logo.png"), b"png").unwrap();
+        fs::write(dir.join("products").join("manual.pdf"), b"pdf").unwrap();
+        let manifest = dir.join(".manifest.json");
+
+        let (client, transport) = recording_client();
+
+        let dir_str = dir.to_str().unwrap();
+        let manifest_str = manifest.to_str().unwrap();
+        assert_eq!(upload_assets(dir_str, manifest_str, &client).unwrap(), 2);
+        assert_eq!(upload_assets(dir_str, manifest_str, &client).unwrap(), 0);
+
+        // Only the changed file goes up again
+        fs::write(dir.join("logo.png"), b"png2").unwrap();
+        assert_eq!(upload_assets(dir_str, manifest_str, &client).unwrap(), 1);
+
+        assert_eq!(transport.get_requests().len(), 3);
+        assert!(transport.get_requests()[0].0.contains("/me/message_attachments"));
+        assert!(matches!(client.get_asset("products/manual"), Some((MediaType::FILE, _))));
+        assert!(matches!(client.get_asset("logo"), Some((MediaType::IMAGE, _))));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
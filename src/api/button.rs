@@ -62,7 +62,7 @@ impl Button {
     }
 
     pub fn new_button_url(name: &str, url: &str) -> Button {
-        Button::PAYLOAD(String::from(name),String::from(url))
+        Button::URL(String::from(name),String::from(url))
     }
 
     pub fn to_json_str(&self) -> String {
@@ -5,8 +5,20 @@ use serde_json::Value;
 pub trait Card: Send + Sync {
     fn to_json(&self) -> Value;
     fn typed(&self) -> &'static str ;
+
+    fn get_buttons(&self) -> Vec<Button> {
+        Vec::new()
+    }
+
+    // Mistakes making the Send API refuse the card, or to_json panic
+    fn lint(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
+// A template accepts up to 3 buttons
+const MAX_BUTTONS: usize = 3;
+
 #[derive(Clone)]
 pub struct DefaultAction {
     status: &'static str,
@@ -65,6 +77,19 @@ impl Card for CardGeneric {
     fn typed(&self) -> &'static str {
         "generic"
     }
+    fn get_buttons(&self) -> Vec<Button> {
+        self.buttons.clone().unwrap_or_default()
+    }
+    fn lint(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        if self.subtitle.is_none() {
+            issues.push(format!("card {} has no subtitle", self.title));
+        }
+        if self.get_buttons().len() > MAX_BUTTONS {
+            issues.push(format!("card {} has more than {} buttons", self.title, MAX_BUTTONS));
+        }
+        issues
+    }
 }
 
 impl Serialize for CardGeneric {
@@ -136,6 +161,16 @@ impl Card for CardButtons {
     fn typed(&self) -> &'static str {
         "buttons"
     }
+    fn get_buttons(&self) -> Vec<Button> {
+        self.buttons.clone().unwrap_or_default()
+    }
+    fn lint(&self) -> Vec<String> {
+        match self.get_buttons().len() {
+            0 => vec![String::from("card has no button")],
+            n if n > MAX_BUTTONS => vec![format!("card has more than {} buttons", MAX_BUTTONS)],
+            _ => Vec::new(),
+        }
+    }
 }
 
 impl CardButtons {
@@ -0,0 +1,292 @@
+use super::response::{SendResponse, SendError, GraphApiError};
+use super::retry::RetryPolicy;
+use super::transport::{Transport, UreqTransport};
+use super::media::MediaType;
+use super::WindowPolicy;
+use crate::utils::Conf;
+use serde_json::Value;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Fields of the user profile a template may use
+const PROFILE_FIELDS: [&str; 3] = ["first_name", "last_name", "profile_pic"];
+
+// Everything a PipeBox needs to talk to the Send API
+#[derive(Clone)]
+pub struct Client {
+    token: String,
+    graph_url: String,
+    graph_version: String,
+    retry: RetryPolicy,
+    transport: Arc<dyn Transport>,
+    attachments: Arc<Mutex<HashMap<String,String>>>,
+    assets: Arc<Mutex<HashMap<String,(MediaType,String)>>>,
+    profiles: Arc<Mutex<HashMap<String,HashMap<String,String>>>>,
+    template_fallback: String,
+    window_policy: WindowPolicy,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client{
+            token: String::from(""),
+            graph_url: String::from("https://graph.facebook.com"),
+            graph_version: String::from("v9.0"),
+            retry: RetryPolicy::default(),
+            transport: Arc::new(UreqTransport),
+            attachments: Arc::new(Mutex::new(HashMap::new())),
+            assets: Arc::new(Mutex::new(HashMap::new())),
+            profiles: Arc::new(Mutex::new(HashMap::new())),
+            template_fallback: String::new(),
+            window_policy: WindowPolicy::REFUSE,
+        }
+    }
+}
+
+impl Client {
+    pub fn new(conf: &Conf) -> Self {
+        let mut client = Client::default();
+        client.set_conf(conf);
+        client
+    }
+
+    // Take the settings of the conf, the transport and caches are kept
+    pub fn set_conf(&mut self, conf: &Conf) {
+        self.token = String::from(conf.get_token_fb_page());
+        self.graph_url = String::from(conf.get_graph_url());
+        self.graph_version = String::from(conf.get_graph_version());
+        self.retry = conf.get_retry().clone();
+        self.template_fallback = String::from(conf.get_template_fallback());
+        self.window_policy = conf.get_window_policy().clone();
+    }
+
+    pub fn set_token(&mut self, token: &str) {
+        self.token = String::from(token);
+    }
+
+    pub fn get_token(&self) -> &str {
+        &self.token
+    }
+
+    // Url of a Graph API path like "me/messages" with the access token
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}/{}?access_token={}", self.graph_url, self.graph_version, path, self.token)
+    }
+
+    pub fn set_retry(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn get_retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    // Same client with a single try, for the sends made under the bot lock
+    pub fn without_retry(&self) -> Client {
+        let mut client = self.clone();
+        client.retry = RetryPolicy::none();
+        client
+    }
+
+    pub fn set_window_policy(&mut self, policy: WindowPolicy) {
+        self.window_policy = policy;
+    }
+
+    pub fn set_transport(&mut self, transport: Arc<dyn Transport>) {
+        self.transport = transport;
+    }
+
+    // Attachment id already uploaded for this url
+    pub fn get_attachment(&self, url: &str) -> Option<String> {
+        self.attachments.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn set_attachment(&self, url: &str, id: &str) {
+        self.attachments.lock().unwrap().insert(String::from(url), String::from(id));
+    }
+
+    // Asset uploaded at startup, referenced by its logical name
+    pub fn get_asset(&self, name: &str) -> Option<(MediaType,String)> {
+        self.assets.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn set_asset(&self, name: &str, kind: MediaType, id: &str) {
+        self.assets.lock().unwrap().insert(String::from(name), (kind, String::from(id)));
+    }
+
+    pub fn get_template_fallback(&self) -> &str {
+        &self.template_fallback
+    }
+
+    pub fn get_window_policy(&self) -> &WindowPolicy {
+        &self.window_policy
+    }
+
+    // Field of the user profile (first_name, last_name, profile_pic), fetched
+    // once, a failed fetch is kept too so it isn't repeated on every send
+    pub fn get_profile(&self, sender_id: &str, field: &str) -> Option<String> {
+        if !PROFILE_FIELDS.contains(&field) {
+            return None
+        }
+        if let Some(e) = self.profiles.lock().unwrap().get(sender_id) {
+            return e.get(field).cloned()
+        }
+
+        let url = format!("{}&fields={}", self.url(sender_id), PROFILE_FIELDS.join(","));
+        let profile: HashMap<String,String> = match self.transport.get_json(&url).and_then(|(status, body)| parse_json(status, body)) {
+            Ok(e) => e.as_object().map(|x| {
+                x.iter().filter_map(|(k,v)| v.as_str().map(|v| (k.clone(), String::from(v)))).collect()
+            }).unwrap_or_default(),
+            Err(e) => {
+                warn!("Profile of {} not fetched: {}", sender_id, e);
+                HashMap::new()
+            }
+        };
+
+        let value = profile.get(field).cloned();
+        self.profiles.lock().unwrap().insert(String::from(sender_id), profile);
+        value
+    }
+
+    // Post a json to the Send API, retrying the transient failures
+    pub fn send_json(&self, value: Value) -> Result<SendResponse, SendError> {
+        let url = self.url("me/messages");
+        info!("Json value : {}",value.to_string());
+        self.retry(|| self.transport.post_json(&url, &value))
+    }
+
+    // Upload a file to the Attachment Upload API, give back its attachment id
+    pub fn upload(&self, kind: &MediaType, file_name: &str, data: &[u8]) -> Result<String, SendError> {
+        let url = self.url("me/message_attachments");
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_nanos()).unwrap_or_default();
+        let boundary = format!("----botMessenger{:x}", nanos);
+        let message = json!({"attachment": {"type": kind, "payload": {"is_reusable": true}}});
+
+        let mut body = Vec::new();
+        body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"message\"\r\n\r\n{}\r\n", boundary, message).as_bytes());
+        body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"filedata\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n"
+            , boundary, file_name, MediaType::from_file_name(file_name).1).as_bytes());
+        body.extend(data);
+        body.extend(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        info!("Upload {} ({} bytes)", file_name, data.len());
+        let resp = self.retry(|| self.transport.post_form(&url, &content_type, &body))?;
+
+        match resp.get_attachment_id() {
+            Some(e) => Ok(String::from(e)),
+            None => Err(SendError::RESPONSE(200, String::from("Upload answer doesn't have an attachment_id"))),
+        }
+    }
+
+    // Call the transport until success, a permanent error or the last attempt
+    fn retry<F>(&self, post: F) -> Result<SendResponse, SendError>
+    where
+        F: Fn() -> Result<(u16,String), SendError>,
+    {
+        if self.token.is_empty() {
+            warn!("Message doesn't have a access_token");
+            return Err(SendError::NOTOKEN)
+        }
+
+        let mut attempt = 1;
+        loop {
+            match post().and_then(|(status, body)| parse(status, body)) {
+                Err(e) if e.is_retryable() && attempt < self.retry.get_max_attempts() => {
+                    let delay = self.retry.delay(attempt);
+                    warn!("Retry {} in {:?} after: {}", attempt, delay, e);
+                    thread::sleep(delay);
+                    attempt += 1;
+                },
+                Err(e) => {
+                    warn!("error: {}", e);
+                    return Err(e)
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+// Turn a Graph answer into a response or a typed error
+fn parse(status: u16, body: String) -> Result<SendResponse, SendError> {
+    let json = parse_json(status, body)?;
+    info!("success: {}", json);
+    Ok(SendResponse::from_json(&json))
+}
+
+fn parse_json(status: u16, body: String) -> Result<Value, SendError> {
+    let json: Value = match serde_json::from_str(&body) {
+        Ok(e) => e,
+        Err(_) => return Err(SendError::RESPONSE(status, body)),
+    };
+
+    if let Some(e) = GraphApiError::from_json(&json) {
+        Err(SendError::GRAPH(status, e))
+    }
+    else if status >= 200 && status < 300 {
+        Ok(json)
+    }
+    else {
+        Err(SendError::RESPONSE(status, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Client;
+    use crate::api::retry::RetryPolicy;
+    use crate::api::transport::{RecordingTransport, recording_client};
+    use crate::api::response::SendError;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn client(attempts: u32) -> (Client, Arc<RecordingTransport>) {
+        let (mut client, transport) = recording_client();
+        client.set_retry(RetryPolicy::new(attempts, Duration::from_millis(0), Duration::from_millis(0), false));
+        (client, transport)
+    }
+
+    #[test]
+    fn retry_then_success() {
+        let (client, transport) = client(3);
+        transport.respond(500, r#"{"error":{"message":"down","type":"OAuthException","code":2,"fbtrace_id":"a"}}"#);
+        transport.respond(400, r#"{"error":{"message":"limit","type":"OAuthException","code":613,"fbtrace_id":"b"}}"#);
+
+        let resp = client.send_json(json!({"recipient": {"id": "1"}})).ok().unwrap();
+
+        assert_eq!(transport.get_requests().len(), 3);
+        assert_eq!(resp.get_recipient_id(), "1");
+    }
+
+    #[test]
+    fn no_retry_on_permanent_error() {
+        let (client, transport) = client(3);
+        transport.respond(400, r#"{"error":{"message":"unreachable","type":"OAuthException","code":551,"fbtrace_id":"a"}}"#);
+
+        let resp = client.send_json(json!({"recipient": {"id": "1"}}));
+
+        assert_eq!(transport.get_requests().len(), 1);
+        assert_eq!(transport.get_requests()[0].0, "https://graph.facebook.com/v9.0/me/messages?access_token=token");
+        assert!(matches!(resp, Err(SendError::GRAPH(400, _))));
+    }
+
+    #[test]
+    fn profile_fetched_once() {
+        let (client, transport) = client(1);
+        transport.respond(500, r#"{"error":{"message":"down","type":"OAuthException","code":2,"fbtrace_id":"a"}}"#);
+
+        // Not a profile field, nothing to fetch
+        assert_eq!(client.get_profile("1", "item"), None);
+        assert!(transport.get_requests().is_empty());
+
+        // A failure isn't fetched again
+        assert_eq!(client.get_profile("1", "first_name"), None);
+        assert_eq!(client.get_profile("1", "last_name"), None);
+        assert_eq!(transport.get_requests().len(), 1);
+    }
+}
@@ -0,0 +1,66 @@
+use serde::ser::{Serialize ,Serializer};
+use std::fmt;
+
+#[derive(Clone,PartialEq)]
+pub enum MediaType {
+    IMAGE,
+    VIDEO,
+    AUDIO,
+    FILE,
+}
+
+impl MediaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::IMAGE => "image",
+            MediaType::VIDEO => "video",
+            MediaType::AUDIO => "audio",
+            MediaType::FILE => "file",
+        }
+    }
+}
+
+impl Serialize for MediaType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"{}",self.as_str())
+    }
+}
+
+impl MediaType {
+    // Guess the type and the mime type from the extension of a file
+    pub fn from_file_name(file_name: &str) -> (Self, &'static str) {
+        let extension = file_name.rsplit('.').next().unwrap_or_default().to_lowercase();
+        match extension.as_str() {
+            "jpg" | "jpeg" => (MediaType::IMAGE, "image/jpeg"),
+            "png" => (MediaType::IMAGE, "image/png"),
+            "gif" => (MediaType::IMAGE, "image/gif"),
+            "webp" => (MediaType::IMAGE, "image/webp"),
+            "mp4" => (MediaType::VIDEO, "video/mp4"),
+            "mov" => (MediaType::VIDEO, "video/quicktime"),
+            "webm" => (MediaType::VIDEO, "video/webm"),
+            "avi" => (MediaType::VIDEO, "video/x-msvideo"),
+            "mp3" => (MediaType::AUDIO, "audio/mpeg"),
+            "wav" => (MediaType::AUDIO, "audio/wav"),
+            "ogg" => (MediaType::AUDIO, "audio/ogg"),
+            "m4a" | "aac" => (MediaType::AUDIO, "audio/aac"),
+            "pdf" => (MediaType::FILE, "application/pdf"),
+            _ => (MediaType::FILE, "application/octet-stream"),
+        }
+    }
+}
+
+// Image, video, audio or file sent from an url or from an uploaded asset
+#[derive(Clone)]
+pub enum Media {
+    URL(MediaType,String),
+    ASSET(String),
+}
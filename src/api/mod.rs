@@ -1,17 +1,31 @@
 use crate::utils;
 pub mod button;
 pub mod card;
+pub mod response;
+pub mod retry;
+pub mod client;
+pub mod transport;
+pub mod media;
+pub mod asset;
 
 use button::Button;
 use card::Card;
+use media::Media;
+use response::{SendResponse, SendError};
+use client::Client;
 use utils::{BotUser};
+use utils::template::{render, render_json};
+use utils::session::now;
 use serde::ser::{Serialize ,Serializer};
 use serde_json::Value;
-use log::{info, warn};
 use std::fmt;
-use ureq::*;
 use std::sync::Arc;
+use std::time::Duration;
 
+// Time after the last message of a user during which the page may write freely
+pub const MESSAGING_WINDOW: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Clone)]
 pub enum MessagingType {
     RESPONSE,
     UPDATE,
@@ -41,8 +55,84 @@ impl Serialize for MessagingType {
     }
 }
 
+// Reasons Messenger accepts for a message out of the 24h window
+#[derive(Clone, PartialEq, Debug)]
+pub enum MessageTag {
+    CONFIRMEDEVENTUPDATE,
+    POSTPURCHASEUPDATE,
+    ACCOUNTUPDATE,
+    HUMANAGENT,
+}
+
+impl fmt::Display for MessageTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageTag::CONFIRMEDEVENTUPDATE => write!(f,"CONFIRMED_EVENT_UPDATE"),
+            MessageTag::POSTPURCHASEUPDATE => write!(f,"POST_PURCHASE_UPDATE"),
+            MessageTag::ACCOUNTUPDATE => write!(f,"ACCOUNT_UPDATE"),
+            MessageTag::HUMANAGENT => write!(f,"HUMAN_AGENT"),
+        }
+    }
+}
+
+impl Serialize for MessageTag {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+// What to do with an untagged message once the 24h window is closed
+#[derive(Clone, PartialEq, Debug)]
+pub enum WindowPolicy {
+    REFUSE,
+    TAG(MessageTag),
+}
+
+#[derive(Clone)]
+pub enum SenderAction {
+    TYPINGON,
+    TYPINGOFF,
+    MARKSEEN,
+}
+
+impl fmt::Display for SenderAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SenderAction::TYPINGON => write!(f,"typing_on"),
+            SenderAction::TYPINGOFF => write!(f,"typing_off"),
+            SenderAction::MARKSEEN => write!(f,"mark_seen"),
+        }
+    }
+}
+
+impl Serialize for SenderAction {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl ApiMessage for SenderAction {
+    fn send(&self, user: &BotUser, client: &Client) -> Result<SendResponse, SendError> {
+        let json = json!(
+            {
+                "recipient": {
+                    "id": user.get_sender()
+                },
+                "sender_action": self
+            }
+        );
+        client.send_json(json)
+    }
+}
+
 pub trait ApiMessage {
-    fn send(&self, user: &BotUser, token: &str);
+    fn send(&self, user: &BotUser, client: &Client) -> Result<SendResponse, SendError>;
 }
 
 #[derive(Clone)]
@@ -50,32 +140,18 @@ pub struct Message {
     text: Option<String>,
     buttons: Option<Vec<Button>>,
     cards: Option<Vec<Arc<dyn Card>>>,
+    media: Option<Media>,
+    messaging_type: MessagingType,
+    tag: Option<MessageTag>,
 }
 
 impl ApiMessage for Message {
-    fn send(&self, user: &BotUser, token: &str) {
-
-        fn send_json(value: serde_json::Value, token: &str) {
-            let url = format!("https://graph.facebook.com/v9.0/me/messages?access_token={}",token);
-            info!("Json value : {}",value.to_string());
-            let resp = ureq::post(&url)
-                .send_json(value);
-
-                if resp.ok() {
-                    info!("success: {}", resp.into_string().unwrap());
-                  } else {
-                    warn!("error {}: {}", resp.status(), resp.into_string().unwrap());
-                  }
-        }
-
-        if token.is_empty() {
-            warn!("Message doesn't have a access_token");
-        }
-        else if self.text.is_some() {
+    fn send(&self, user: &BotUser, client: &Client) -> Result<SendResponse, SendError> {
+        if self.text.is_some() {
             
-            let json =  self::json!(
+            let json =  json!(
                 {
-                    "messaging_type": MessagingType::RESPONSE,
+                    "messaging_type": self.messaging_type,
                     "recipient": {
                         "id": user.get_sender()
                     },
@@ -85,27 +161,27 @@ impl ApiMessage for Message {
                     }
                 }
             );
-            send_json(json,token);
+            send_rendered(json, self.tag.as_ref(), user, client)
         }
         else if self.cards.is_some() {
             let card =  self.cards.as_ref().unwrap();
             let cards: Vec<Value> = card.iter().map(|e| e.clone().to_json()).collect();
             let payload: Value = match card[0].typed() {
                 "generic" => {
-                    self::json!({"template_type": "generic", "elements": cards})
+                    json!({"template_type": "generic", "elements": cards})
                 },
                 "buttons" => {
                     let value: Value = card[0].clone().to_json();
                     value
                 }
                 _ => {
-                    self::json!({})
+                    json!({})
                 }
             };
 
-            let json =  self::json!(
+            let json =  json!(
                 {
-                    "messaging_type": MessagingType::RESPONSE,
+                    "messaging_type": self.messaging_type,
                     "recipient": {
                         "id": user.get_sender()
                     },
@@ -117,17 +193,171 @@ impl ApiMessage for Message {
                     }
                 }
             );
-            send_json(json,token);
+            send_rendered(json, self.tag.as_ref(), user, client)
+        }
+        else if let Some(media) = &self.media {
+            // Reuse the attachment uploaded by a previous send of the same
+            // url, once its {{variables}} are filled for this user
+            let (kind, payload, url) = match media {
+                Media::URL(kind,url) => {
+                    let url = render(url, &lookup(user, client), client.get_template_fallback());
+                    match client.get_attachment(&url) {
+                        Some(id) => (kind.clone(), json!({"attachment_id": id}), None),
+                        None => (kind.clone(), json!({"url": url, "is_reusable": true}), Some(url)),
+                    }
+                },
+                Media::ASSET(name) => match client.get_asset(name) {
+                    Some((kind,id)) => (kind, json!({"attachment_id": id}), None),
+                    None => return Err(SendError::ASSET(name.clone())),
+                },
+            };
+
+            let json =  json!(
+                {
+                    "messaging_type": self.messaging_type,
+                    "recipient": {
+                        "id": user.get_sender()
+                    },
+                    "message": {
+                        "attachment": {
+                            "type": kind,
+                            "payload": payload
+                        },
+                        "quick_replies": self.buttons,
+                    }
+                }
+            );
+
+            let resp = send_rendered(json, self.tag.as_ref(), user, client)?;
+            if let (Some(url), Some(id)) = (url, resp.get_attachment_id()) {
+                client.set_attachment(&url, id);
+            }
+            Ok(resp)
+        }
+        else {
+            Err(SendError::EMPTY)
         }
     }
 }
 
+// Tag the message or refuse it when the user wrote more than 24h ago,
+// then fill the {{variables}} from the session, then from the user profile
+fn send_rendered(mut json: Value, tag: Option<&MessageTag>, user: &BotUser, client: &Client) -> Result<SendResponse, SendError> {
+    let outside = user.get_received()
+        .map(|at| now().saturating_sub(at) > MESSAGING_WINDOW.as_secs())
+        .unwrap_or(false);
+    let tag = match (tag, client.get_window_policy()) {
+        (Some(tag), _) => Some(tag),
+        (None, _) if !outside => None,
+        (None, WindowPolicy::TAG(tag)) => Some(tag),
+        (None, WindowPolicy::REFUSE) => return Err(SendError::WINDOW(String::from(user.get_sender()))),
+    };
+    if let Some(tag) = tag {
+        json["messaging_type"] = json!(MessagingType::MESSAGETAG);
+        json["tag"] = json!(tag);
+    }
+    render_json(&mut json["message"], &lookup(user, client), client.get_template_fallback());
+    client.send_json(json)
+}
+
+// Value of a {{variable}} from the session, then from the user profile
+fn lookup<'a>(user: &'a BotUser, client: &'a Client) -> impl Fn(&str) -> Option<String> + 'a {
+    move |name: &str| {
+        user.get_var(name).map(String::from)
+            .or_else(|| client.get_profile(user.get_sender(), name))
+    }
+}
+
 impl Message {
     pub fn new(text : Option<String>,buttons: Option<Vec<Button>>, cards: Option<Vec<Arc<dyn Card>>>) -> Self {
         Message{
             text: text,
             buttons: buttons,
-            cards: cards
+            cards: cards,
+            media: None,
+            messaging_type: MessagingType::RESPONSE,
+            tag: None,
         }
     }
-}
\ No newline at end of file
+
+    pub fn with_media(mut self, media: Media) -> Self {
+        self.media = Some(media);
+        self
+    }
+
+    // UPDATE for a message the user didn't just ask for, like a follow up
+    pub fn with_messaging_type(mut self, messaging_type: MessagingType) -> Self {
+        self.messaging_type = messaging_type;
+        self
+    }
+
+    // Allowed out of the 24h window, only for what the tag covers
+    pub fn with_tag(mut self, tag: MessageTag) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+}
+#[cfg(test)]
+mod tests {
+
+    use super::{ApiMessage, Message, MessageTag, WindowPolicy};
+    use super::media::{Media, MediaType};
+    use super::response::SendError;
+    use super::transport::recording_client;
+    use crate::utils::{BotUser, MessagingMessage};
+    use crate::utils::session::now;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn window_and_tags() {
+        let (mut client, transport) = recording_client();
+        let user = |received: u64| BotUser::new("1", Arc::new(MessagingMessage::new("")))
+            .with_received(received);
+        let message = Message::new(Some(String::from("Your order shipped")), None, None);
+
+        // Inside the window nothing changes
+        message.send(&user(now() - 3600), &client).ok().unwrap();
+        assert_eq!(transport.get_bodies().last().unwrap()["messaging_type"], "RESPONSE");
+        assert!(transport.get_bodies().last().unwrap().get("tag").is_none());
+
+        // Out of it the untagged message is refused
+        match message.send(&user(now() - 25 * 3600), &client) {
+            Err(SendError::WINDOW(e)) => assert_eq!(e, "1"),
+            _ => panic!("sent out of the window"),
+        }
+        assert_eq!(transport.get_bodies().len(), 1);
+
+        // The tag of the message lets it through
+        message.clone().with_tag(MessageTag::POSTPURCHASEUPDATE).send(&user(now() - 25 * 3600), &client).ok().unwrap();
+        assert_eq!(transport.get_bodies().last().unwrap()["messaging_type"], "MESSAGE_TAG");
+        assert_eq!(transport.get_bodies().last().unwrap()["tag"], "POST_PURCHASE_UPDATE");
+
+        // Or the policy re-tags it
+        client.set_window_policy(WindowPolicy::TAG(MessageTag::ACCOUNTUPDATE));
+        message.send(&user(now() - 25 * 3600), &client).ok().unwrap();
+        assert_eq!(transport.get_bodies().last().unwrap()["tag"], "ACCOUNT_UPDATE");
+    }
+
+    #[test]
+    fn media_url_rendered() {
+        let (client, transport) = recording_client();
+        let user = |id: &str, size: &str| {
+            let vars: HashMap<String,String> = vec![(String::from("size"), String::from(size))].into_iter().collect();
+            BotUser::new(id, Arc::new(MessagingMessage::new(""))).with_vars(&vars)
+        };
+        let message = Message::new(None, None, None)
+            .with_media(Media::URL(MediaType::IMAGE, String::from("https://example.com/{{size}}.png")));
+
+        // The attachment is kept for the url of this user only
+        transport.respond(200, r#"{"recipient_id":"1","message_id":"mid.1","attachment_id":"111"}"#);
+        message.send(&user("1", "small"), &client).ok().unwrap();
+        message.send(&user("2", "large"), &client).ok().unwrap();
+        message.send(&user("3", "small"), &client).ok().unwrap();
+
+        let payloads: Vec<_> = transport.get_bodies().iter().map(|x| x["message"]["attachment"]["payload"].clone()).collect();
+        assert_eq!(payloads[0]["url"], "https://example.com/small.png");
+        assert_eq!(payloads[1]["url"], "https://example.com/large.png");
+        assert_eq!(payloads[2], json!({"attachment_id": "111"}));
+    }
+}
@@ -0,0 +1,178 @@
+use serde_json::Value;
+use std::fmt;
+
+// Answer of the Send API when Facebook accepted the message
+#[derive(Clone)]
+pub struct SendResponse {
+    recipient_id: String,
+    message_id: String,
+    attachment_id: Option<String>,
+}
+
+impl SendResponse {
+    pub fn from_json(json: &Value) -> Self {
+        SendResponse{
+            recipient_id: json["recipient_id"].as_str().unwrap_or_default().to_string(),
+            message_id: json["message_id"].as_str().unwrap_or_default().to_string(),
+            attachment_id: json["attachment_id"].as_str().map(String::from),
+        }
+    }
+
+    pub fn get_recipient_id(&self) -> &str {
+        &self.recipient_id
+    }
+
+    pub fn get_message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    // Only set when a reusable attachment was uploaded
+    pub fn get_attachment_id(&self) -> Option<&str> {
+        self.attachment_id.as_deref()
+    }
+}
+
+impl fmt::Display for SendResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Send Response : [ Recipient id: {} , Message id: {} ]"
+            , self.recipient_id, self.message_id)
+    }
+}
+
+// Error object of the Graph API
+#[derive(Clone)]
+pub struct GraphApiError {
+    message: String,
+    kind: String,
+    code: i64,
+    error_subcode: Option<i64>,
+    is_transient: bool,
+    fbtrace_id: String,
+}
+
+// Graph codes worth a retry: unknown, service down and the rate limits
+const RETRYABLE_CODES: [i64; 7] = [1, 2, 4, 17, 32, 341, 613];
+
+// Graph codes that will fail again: bad parameter, permission, token, user not reachable
+const PERMANENT_CODES: [i64; 6] = [10, 100, 190, 200, 551, 2018001];
+
+impl GraphApiError {
+    // Parse the "error" object of a Graph answer
+    pub fn from_json(json: &Value) -> Option<Self> {
+        let error = json.get("error")?;
+        Some(GraphApiError{
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+            kind: error["type"].as_str().unwrap_or_default().to_string(),
+            code: error["code"].as_i64()?,
+            error_subcode: error["error_subcode"].as_i64(),
+            is_transient: error["is_transient"].as_bool().unwrap_or(false),
+            fbtrace_id: error["fbtrace_id"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    // Graph "type" field, like OAuthException
+    pub fn get_kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn get_code(&self) -> i64 {
+        self.code
+    }
+
+    pub fn get_error_subcode(&self) -> Option<i64> {
+        self.error_subcode
+    }
+
+    pub fn get_fbtrace_id(&self) -> &str {
+        &self.fbtrace_id
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.is_transient
+    }
+}
+
+impl fmt::Display for GraphApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (type: {}, code: {}, subcode: {}, fbtrace_id: {})"
+            , self.message, self.kind, self.code
+            , self.error_subcode.map(|x| x.to_string()).unwrap_or_else(|| String::from("none"))
+            , self.fbtrace_id)
+    }
+}
+
+#[derive(Clone)]
+pub enum SendError {
+    NOTOKEN,
+    EMPTY,
+    ASSET(String),
+    NETWORK(String),
+    GRAPH(u16,GraphApiError),
+    RESPONSE(u16,String),
+    // Out of the 24h window without a tag, for this user
+    WINDOW(String),
+}
+
+impl SendError {
+    // Only the failures that can succeed on a new try
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SendError::NETWORK(_) => true,
+            SendError::GRAPH(status,e) => {
+                if PERMANENT_CODES.contains(&e.get_code()) {
+                    false
+                }
+                else {
+                    e.is_transient() || RETRYABLE_CODES.contains(&e.get_code()) || *status >= 500
+                }
+            },
+            SendError::RESPONSE(status,_) => *status >= 500 || *status == 429,
+            SendError::NOTOKEN | SendError::EMPTY | SendError::ASSET(_) | SendError::WINDOW(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NOTOKEN => write!(f,"Message doesn't have a access_token"),
+            SendError::EMPTY => write!(f,"Message doesn't have anything to send"),
+            SendError::ASSET(e) => write!(f,"Asset {} was never uploaded",e),
+            SendError::NETWORK(e) => write!(f,"Network error: {}",e),
+            SendError::GRAPH(status,e) => write!(f,"Graph error {}: {}",status,e),
+            SendError::RESPONSE(status,e) => write!(f,"Unexpected answer {}: {}",status,e),
+            SendError::WINDOW(e) => write!(f,"Last message of {} is older than 24h, the message needs a tag",e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{SendError, GraphApiError};
+
+    fn graph(status: u16, code: i64) -> SendError {
+        let json = json!({"error": {"message": "error", "type": "OAuthException", "code": code, "fbtrace_id": "trace"}});
+        SendError::GRAPH(status, GraphApiError::from_json(&json).unwrap())
+    }
+
+    #[test]
+    fn retryable_errors() {
+        assert!(graph(400, 613).is_retryable());
+        assert!(graph(500, 2).is_retryable());
+        assert!(SendError::NETWORK(String::from("reset")).is_retryable());
+        assert!(SendError::RESPONSE(502, String::from("Bad gateway")).is_retryable());
+    }
+
+    #[test]
+    fn permanent_errors() {
+        assert!(!graph(400, 551).is_retryable());
+        assert!(!graph(500, 190).is_retryable());
+        assert!(!graph(400, 100).is_retryable());
+        assert!(!SendError::NOTOKEN.is_retryable());
+    }
+}
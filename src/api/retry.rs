@@ -0,0 +1,58 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How the Send API retries a transient failure
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy{
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // New RetryPolicy struct, max_attempts counts the first try
+    pub fn new(max_attempts: u32, base_delay: Duration, max_delay: Duration, jitter: bool) -> Self {
+        RetryPolicy{
+            max_attempts: max_attempts.max(1),
+            base_delay: base_delay,
+            max_delay: max_delay,
+            jitter: jitter,
+        }
+    }
+
+    // Only one try
+    pub fn none() -> Self {
+        RetryPolicy::new(1, Duration::from_millis(0), Duration::from_millis(0), false)
+    }
+
+    pub fn get_max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    // Wait before the retry number `attempt` (1 is the first retry)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.checked_mul(factor).unwrap_or(self.max_delay).min(self.max_delay);
+
+        if self.jitter {
+            // Between half and the full delay, the clock is random enough here
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.subsec_nanos()).unwrap_or_default();
+            let half = delay / 2;
+            half + half.mul_f64((nanos % 1000) as f64 / 1000.0)
+        }
+        else {
+            delay
+        }
+    }
+}
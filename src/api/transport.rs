@@ -0,0 +1,150 @@
+use super::response::SendError;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+#[cfg(test)]
+use super::{client::Client, retry::RetryPolicy};
+#[cfg(test)]
+use std::{sync::Arc, time::Duration};
+#[cfg(test)]
+use crate::utils::{BotUser, MessagingMessage};
+
+// Carry a json to the Graph API and give back the http status and body
+pub trait Transport: Send + Sync {
+    fn post_json(&self, url: &str, body: &Value) -> Result<(u16,String), SendError>;
+
+    // Raw body like the multipart form of the Attachment Upload API
+    fn post_form(&self, url: &str, content_type: &str, body: &[u8]) -> Result<(u16,String), SendError>;
+
+    // Read a Graph object like the profile of a user
+    fn get_json(&self, url: &str) -> Result<(u16,String), SendError>;
+}
+
+// Default transport, talk to Facebook with ureq
+#[derive(Clone, Default)]
+pub struct UreqTransport;
+
+impl Transport for UreqTransport {
+    fn post_json(&self, url: &str, body: &Value) -> Result<(u16,String), SendError> {
+        let resp = ureq::post(url)
+            .send_json(body.clone());
+
+        if let Some(e) = resp.synthetic_error() {
+            return Err(SendError::NETWORK(e.to_string()))
+        }
+
+        let status = resp.status();
+        Ok((status, resp.into_string().unwrap_or_default()))
+    }
+
+    fn post_form(&self, url: &str, content_type: &str, body: &[u8]) -> Result<(u16,String), SendError> {
+        let resp = ureq::post(url)
+            .set("Content-Type", content_type)
+            .send_bytes(body);
+
+        if let Some(e) = resp.synthetic_error() {
+            return Err(SendError::NETWORK(e.to_string()))
+        }
+
+        let status = resp.status();
+        Ok((status, resp.into_string().unwrap_or_default()))
+    }
+
+    fn get_json(&self, url: &str) -> Result<(u16,String), SendError> {
+        let resp = ureq::get(url)
+            .call();
+
+        if let Some(e) = resp.synthetic_error() {
+            return Err(SendError::NETWORK(e.to_string()))
+        }
+
+        let status = resp.status();
+        Ok((status, resp.into_string().unwrap_or_default()))
+    }
+}
+
+// Client with a token sending through a new RecordingTransport, retrying
+// without delay, for the tests
+#[cfg(test)]
+pub fn recording_client() -> (Client, Arc<RecordingTransport>) {
+    let transport = Arc::new(RecordingTransport::new());
+    let mut client = Client::default();
+    client.set_token("token");
+    client.set_retry(RetryPolicy::new(3, Duration::from_millis(0), Duration::from_millis(0), false));
+    client.set_transport(transport.clone());
+    (client, transport)
+}
+
+// User "1" writing the text, for the tests
+#[cfg(test)]
+pub fn user(text: &str) -> BotUser {
+    BotUser::new("1", Arc::new(MessagingMessage::new(text)))
+}
+
+// In memory transport keeping every request, for the tests
+#[derive(Default)]
+pub struct RecordingTransport {
+    requests: Mutex<Vec<(String,Value)>>,
+    responses: Mutex<VecDeque<(u16,String)>>,
+}
+
+impl Transport for RecordingTransport {
+    fn post_json(&self, url: &str, body: &Value) -> Result<(u16,String), SendError> {
+        let mut requests = self.requests.lock().unwrap();
+        requests.push((String::from(url), body.clone()));
+
+        // Queued answer first, else a success like the Send API one
+        match self.responses.lock().unwrap().pop_front() {
+            Some(e) => Ok(e),
+            None => Ok((200, json!({
+                "recipient_id": body["recipient"]["id"],
+                "message_id": format!("mid.{}", requests.len()),
+            }).to_string())),
+        }
+    }
+
+    // Only the content type and size of the form are kept
+    fn post_form(&self, url: &str, content_type: &str, body: &[u8]) -> Result<(u16,String), SendError> {
+        let mut requests = self.requests.lock().unwrap();
+        requests.push((String::from(url), json!({"content_type": content_type, "length": body.len()})));
+
+        match self.responses.lock().unwrap().pop_front() {
+            Some(e) => Ok(e),
+            None => Ok((200, json!({"attachment_id": format!("{}", requests.len())}).to_string())),
+        }
+    }
+
+    // Without a queued answer the object is empty
+    fn get_json(&self, url: &str) -> Result<(u16,String), SendError> {
+        self.requests.lock().unwrap().push((String::from(url), Value::Null));
+
+        match self.responses.lock().unwrap().pop_front() {
+            Some(e) => Ok(e),
+            None => Ok((200, String::from("{}"))),
+        }
+    }
+}
+
+impl RecordingTransport {
+    pub fn new() -> Self {
+        RecordingTransport::default()
+    }
+
+    // Queue the answer of the next request
+    pub fn respond(&self, status: u16, body: &str) {
+        self.responses.lock().unwrap().push_back((status, String::from(body)));
+    }
+
+    // Url and body of every request, in order
+    pub fn get_requests(&self) -> Vec<(String,Value)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn get_bodies(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().iter().map(|x| x.1.clone()).collect()
+    }
+
+    pub fn clear(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+}
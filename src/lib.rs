@@ -8,14 +8,49 @@ pub mod utils;
 pub mod api;
 
 use utils::block::Block;
-use utils::{Conf, BotUser};
-use rocket_contrib::json::{Json};
+use utils::{Conf, BotUser, Webhook, MessagingType, MessagingMessage};
+use utils::signature::verify_signature;
+use utils::session::{Session, SessionStore, MemoryStore, FollowUp, now, now_millis};
+use utils::flow::{Flow, FlowError};
+use utils::lint::{lint, Issue};
+use utils::export::{to_dot, to_mermaid};
+use utils::intent::{Intent, IntentRecognizer, TfIdfRecognizer};
+use api::{ApiMessage, Message, SenderAction, WindowPolicy};
+use api::client::Client;
+use api::asset::upload_assets;
+use api::retry::RetryPolicy;
+use api::transport::Transport;
 use rocket_contrib::serve::{StaticFiles, Options};
 use rocket::config::{Config, Environment};
-use rocket::State;
-use log::{info, warn};
-use rocket::request::Form;
+use rocket::{State, Data};
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, Form, FromRequest, Request};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::io::Read;
+use std::fs;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Max size of a webhook body read before the signature check
+const BODY_LIMIT: u64 = 1024 * 1024;
+
+// Max GOTO followed for one message, stops blocks jumping in a loop
+const MAX_JUMPS: usize = 16;
+
+// How long an expired session waits for the welcome back without a global ttl
+const EXPIRED_KEEP: Duration = Duration::from_secs(30 * 24 * 3600);
+
+// How often the stale sessions are evicted and the follow ups sent
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often the flow file is checked for changes
+const FLOW_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+// How often the PipeBoxes on a typing delay or a retry backoff are checked
+const RESUME_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone)]
 pub struct BotMessenger {
@@ -23,6 +58,15 @@ pub struct BotMessenger {
     blocks: Vec<Block>,
     block_default: Block,
     static_file: Option<String>,
+    assets: Option<(String,String)>,
+    event_hooks: Vec<Arc<dyn Fn(&BotUser) + Send + Sync>>,
+    client: Client,
+    sessions: Arc<dyn SessionStore>,
+    recognizer: Option<Arc<dyn IntentRecognizer>>,
+    local_recognizer: TfIdfRecognizer,
+    flow_file: Option<String>,
+    // Unix time in milliseconds when the paused PipeBox of a user goes on
+    resumes: Arc<Mutex<HashMap<String,u64>>>,
 }
 
 impl Drop for BotMessenger {
@@ -35,39 +79,201 @@ impl BotMessenger {
 
     // New BotMessenger struct
     pub fn new() -> Self {
+        let client = Client::new(&Conf::default());
+        let sessions: Arc<dyn SessionStore> = Arc::new(MemoryStore::new());
+
+        // The implicit default block shares the store and client of the bot
+        let mut block_default = Block::default();
+        block_default.set_client(client.clone());
+        block_default.set_store(sessions.clone());
+
         BotMessenger {
             conf: Conf::default(),
             blocks: Vec::new(),
-            block_default: Block::default(),
+            block_default: block_default,
             static_file: None,
+            assets: None,
+            event_hooks: Vec::new(),
+            client: client,
+            sessions: sessions,
+            recognizer: None,
+            local_recognizer: TfIdfRecognizer::default(),
+            flow_file: None,
+            resumes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Bot with the blocks of a YAML or JSON flow file, see utils::flow::Flow
+    pub fn from_flow_file(path: &str) -> Result<Self, FlowError> {
+        let (blocks, default) = Flow::load(path)?.build();
+
+        let mut bot = BotMessenger::new();
+        for block in blocks {
+            bot = bot.block(block);
+        }
+        if let Some(e) = default {
+            bot = bot.block_default(e);
+        }
+        bot.flow_file = Some(String::from(path));
+        Ok(bot)
+    }
+
+    // Swap in the blocks of the flow file, the old ones stay on error
+    pub fn reload(&mut self) -> Result<(), FlowError> {
+        let path = match &self.flow_file {
+            Some(e) => e.clone(),
+            None => return Ok(()),
+        };
+        let (blocks, default) = Flow::load(&path)?.build();
+
+        // A flow without default keeps the current one
+        let client = self.client();
+        let prepare = |mut x: Block| {
+            x.set_client(client.clone());
+            x.set_store(self.sessions.clone());
+            x
+        };
+        let blocks: Vec<Block> = blocks.into_iter().map(prepare).collect();
+        let default = default.map(prepare).unwrap_or_else(|| self.block_default.clone());
+
+        let errors: Vec<Issue> = lint(&blocks, &default, self.conf.get_welcome_back()).into_iter().filter(|x| x.is_error()).collect();
+        if let Some(e) = errors.first() {
+            return Err(FlowError::new(&format!("{} errors in the flows, first {}", errors.len(), e)))
+        }
+
+        self.blocks = blocks;
+        self.block_default = default;
+
+        self.train();
+        self.migrate_sessions();
+        info!("Flows of {} reloaded, {} blocks", path, self.blocks.len());
+        Ok(())
+    }
+
+    // Keep the users on their step when their block still exists, else
+    // send them to the default block
+    fn migrate_sessions(&self) {
+        for mut session in self.sessions.all() {
+            let name = match session.get_block() {
+                Some(e) => String::from(e),
+                None => continue,
+            };
+
+            match self.get_block(&name) {
+                Some(e) if session.get_index() < e.get_pipe().len() => continue,
+                Some(e) => {
+                    info!("Step of {} gone from {}, back to its start", session.get_sender(), e.get_name());
+                    session.set_index(0);
+                },
+                None => {
+                    info!("Block {} gone, {} goes to the default block", name, session.get_sender());
+                    session.enter(self.block_default.get_name(), 0);
+                },
+            }
+            self.sessions.set(session);
         }
     }
 
     // Add conf struct
     pub fn block(mut self, value: Block) -> Self {
         let mut block = value;
-        block.set_token(self.get_conf().get_token_fb_page());
+        block.set_client(self.client());
+        block.set_store(self.sessions.clone());
         self.add_block(block);
+        self.train();
         self
     }
 
     pub fn block_default(mut self, value: Block) -> Self {
         let mut block = value;
-        block.set_token(self.get_conf().get_token_fb_page());
+        block.set_client(self.client());
+        block.set_store(self.sessions.clone());
         self.block_default = block;
         self
     }
 
     // Add user connection
     pub fn add_user(&mut self, user: BotUser) -> &mut Self {
-        let block_match = self.blocks.iter_mut().find(|x| {
-            x.get_name() == user.get_message().message()
-        });
-        
-        if let Some(i) = block_match {
-            info!("Message match with block");
-            i.remove_child(&user);
-            i.root(&user);
+        if user.get_message().message_type().is_event() {
+            info!("Event {} goes to the hooks", user.get_message().message_type());
+            self.event_hooks.iter().for_each(|x| x(&user));
+            return self
+        }
+
+        // Only a hint, not worth a backoff under the lock
+        if let Err(e) = SenderAction::MARKSEEN.send(&user, &self.client.without_retry()) {
+            warn!("Mark seen not sent: {}", e);
+        }
+
+        // The reply cancels the nudges, and a user silent for too long
+        // doesn't resume an old flow
+        if let Some(mut session) = self.sessions.get(user.get_sender()) {
+            let cancelled = session.cancel_follow_ups();
+            if cancelled > 0 {
+                info!("{} follow ups of {} cancelled", cancelled, user.get_sender());
+            }
+
+            let stale = self.ttl(&session).map(|x| session.is_stale(x)).unwrap_or(false);
+            if session.get_block().is_some() && stale {
+                info!("Session of {} expired in {:?}", user.get_sender(), session.get_block());
+                session.expire();
+            }
+
+            if cancelled > 0 || session.is_expired() {
+                self.sessions.set(session.clone());
+            }
+
+            let welcome = self.conf.get_welcome_back().map(String::from);
+            if let (true, Some(name)) = (session.is_expired(), welcome) {
+                if let Some(block) = self.get_block_mut(&name) {
+                    let goto = block.root(&user);
+                    self.follow(&user, goto);
+                    return self
+                }
+                warn!("No welcome back block {}", name);
+            }
+        }
+
+        // The name restarts a block at any time, the other triggers only
+        // when the user isn't already in one
+        let message = user.get_message();
+        let text = message.message();
+        let in_block = self.blocks.iter().any(|x| x.find(&user));
+        let mut block_match: Option<(usize, usize, HashMap<String,String>)> = None;
+
+        // A confident intent goes first, under the threshold the triggers
+        // and at last the default block take the message
+        if !in_block && matches!(message.message_type(), MessagingType::MESSAGE(_)) {
+            if let Some(intent) = self.recognize(text) {
+                let position = self.blocks.iter().position(|x| x.get_name() == intent.get_name());
+                match position {
+                    Some(i) if intent.get_confidence() >= self.conf.get_intent_threshold() => {
+                        info!("Intent {} recognized at {:.2}", intent.get_name(), intent.get_confidence());
+                        block_match = Some((i, 0, intent.get_entities().clone()));
+                    },
+                    _ => info!("Intent {} ignored at {:.2}", intent.get_name(), intent.get_confidence()),
+                }
+            }
+        }
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            if let Some((priority, vars)) = block.matching(&user) {
+                if (priority == 0 || !in_block) && block_match.as_ref().map(|x| priority < x.1).unwrap_or(true) {
+                    block_match = Some((i, priority, vars));
+                }
+            }
+        }
+
+        let goto = if let Some((i, _, vars)) = block_match {
+            info!("Message match with block {}", self.blocks[i].get_name());
+            if !vars.is_empty() {
+                let mut session = self.sessions.get(user.get_sender())
+                    .unwrap_or_else(|| Session::new(user.get_sender()));
+                vars.iter().for_each(|(k,v)| session.set_var(k, v));
+                self.sessions.set(session);
+            }
+            self.blocks[i].remove_child(&user);
+            self.blocks[i].root(&user)
         }
         else {
             match self.blocks.iter_mut().enumerate().find(|x| {
@@ -75,27 +281,163 @@ impl BotMessenger {
                 {
                     Some(u) => {
                         info!("Find a user match in block");
-                        u.1.root(&user);
+                        u.1.root(&user)
                 },
                     None => {
                         warn!("Don't match with any of blocks");
-                        self.block_default.root(&user);
+                        self.block_default.root(&user)
                 }
             } 
-        }
+        };
 
+        self.follow(&user, goto);
         self
     }
 
+    // Intent of a text message, from the recognizer given or else from the utterances of the blocks
+    fn recognize(&self, text: &str) -> Option<Intent> {
+        match &self.recognizer {
+            Some(e) => e.recognize(text),
+            None if !self.local_recognizer.is_empty() => self.local_recognizer.recognize(text),
+            None => None,
+        }
+    }
+
+    // Rebuild the local recognizer from the utterances of the blocks
+    fn train(&mut self) {
+        let examples: Vec<(String, Vec<String>)> = self.blocks.iter()
+            .filter(|x| !x.get_utterances().is_empty())
+            .map(|x| (String::from(x.get_name()), x.get_utterances().to_vec()))
+            .collect();
+        self.local_recognizer = TfIdfRecognizer::new(&examples);
+    }
+
+    // Follow the GOTO of the blocks, the session is already in the target
+    fn follow(&mut self, user: &BotUser, goto: Option<String>) {
+        let mut goto = goto;
+        let mut jumps = 0;
+
+        while let Some(name) = goto {
+            jumps += 1;
+            if jumps > MAX_JUMPS {
+                warn!("Too many jumps for {}, stop in {}", user.get_sender(), name);
+                break
+            }
+
+            goto = match self.get_block_mut(&name) {
+                Some(e) => e.jump(user),
+                None => {
+                    warn!("No block {} to jump to", name);
+                    if let Some(mut session) = self.sessions.get(user.get_sender()) {
+                        session.leave();
+                        self.sessions.set(session);
+                    }
+                    None
+                }
+            };
+        }
+
+        self.track_resume(user.get_sender());
+    }
+
+    // Keep the time the user goes on at when their PipeBox is paused
+    fn track_resume(&self, sender_id: &str) {
+        let resume = self.sessions.get(sender_id).and_then(|x| x.get_resume());
+        let mut resumes = self.resumes.lock().unwrap();
+        match resume {
+            Some(e) => resumes.insert(String::from(sender_id), e),
+            None => resumes.remove(sender_id),
+        };
+    }
+
+    // Go on with the PipeBoxes whose typing delay or retry backoff is over
+    pub fn resume_due(&mut self) -> usize {
+        let now = now_millis();
+        let due: Vec<String> = self.resumes.lock().unwrap().iter()
+            .filter(|x| *x.1 <= now)
+            .map(|x| x.0.clone())
+            .collect();
+
+        for sender_id in &due {
+            let mut session = match self.sessions.get(sender_id) {
+                Some(e) => e,
+                None => {
+                    self.resumes.lock().unwrap().remove(sender_id);
+                    continue
+                }
+            };
+
+            let user = BotUser::new(sender_id, Arc::new(MessagingMessage::new("")))
+                .with_vars(session.get_vars())
+                .with_received(session.get_received());
+            let block = session.get_block().map(String::from);
+            let goto = match block.as_ref().and_then(|x| self.get_block_mut(x)) {
+                Some(e) => e.resume(&user),
+                None => {
+                    warn!("No block {:?} to resume {} in", block, sender_id);
+                    session.clear_pause();
+                    self.sessions.set(session);
+                    None
+                }
+            };
+            self.follow(&user, goto);
+        }
+        due.len()
+    }
+
     pub fn with_conf(mut self, conf: Conf) -> Self {
         self.conf = conf;
+        self.update_client();
         self
     }
 
     pub fn with_token_fb(mut self, token: &str) -> Self {
         self.conf.set_token_fb_page(token);
-        self.blocks.iter_mut().for_each(|x| x.set_token(token));
-        self.block_default.set_token(token);
+        self.update_client();
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.conf.set_retry(retry);
+        self.update_client();
+        self
+    }
+
+    // Replace the recognizer trained on the utterances of the blocks
+    pub fn with_recognizer(mut self, recognizer: Arc<dyn IntentRecognizer>) -> Self {
+        self.recognizer = Some(recognizer);
+        self
+    }
+
+    pub fn with_intent_threshold(mut self, threshold: f64) -> Self {
+        self.conf.set_intent_threshold(threshold);
+        self
+    }
+
+    // Text of a {{variable}} found neither in the session nor in the profile
+    pub fn with_template_fallback(mut self, fallback: &str) -> Self {
+        self.conf.set_template_fallback(fallback);
+        self.update_client();
+        self
+    }
+
+    // Graph host, to point the bot at a mock server
+    pub fn with_graph_url(mut self, url: &str) -> Self {
+        self.conf.set_graph_url(url);
+        self.update_client();
+        self
+    }
+
+    pub fn with_graph_version(mut self, version: &str) -> Self {
+        self.conf.set_graph_version(version);
+        self.update_client();
+        self
+    }
+
+    // Replace the ureq transport, like a RecordingTransport in the tests
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.client.set_transport(transport);
+        self.update_client();
         self
     }
     
@@ -104,11 +446,64 @@ impl BotMessenger {
         self
     }
 
+    pub fn with_app_secret(mut self, secret: &str) -> Self {
+        self.conf.set_app_secret(secret);
+        self
+    }
+
+    // Without an app secret every webhook POST is rejected, unless this
+    // opts out of the signature check, anyone could then post as any user
+    pub fn allow_unsigned_webhooks(mut self) -> Self {
+        self.conf.set_allow_unsigned(true);
+        self
+    }
+
     pub fn with_port(mut self, port: u16) -> Self {
         self.conf.set_port(port);
         self
     }
 
+    // Time a user may stay silent in a block without their own ttl
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.conf.set_session_ttl(ttl);
+        self
+    }
+
+    // Block started for a user coming back after their session expired,
+    // without it they are routed like a new message
+    pub fn with_welcome_back(mut self, block: &str) -> Self {
+        self.conf.set_welcome_back(block);
+        self
+    }
+
+    // Untagged messages out of the 24h window are refused, unless a tag is
+    // given here to send them with
+    pub fn with_window_policy(mut self, policy: WindowPolicy) -> Self {
+        self.conf.set_window_policy(policy);
+        self.update_client();
+        self
+    }
+
+    // Where the position of each user is kept, like a FileStore to survive restarts
+    pub fn with_session_store(mut self, sessions: Arc<dyn SessionStore>) -> Self {
+        self.blocks.iter_mut().for_each(|x| x.set_store(sessions.clone()));
+        self.block_default.set_store(sessions.clone());
+        self.sessions = sessions;
+        self
+    }
+
+    // Files uploaded at launch, the manifest keeps their attachment ids
+    pub fn with_assets(mut self, dir: &str, manifest: &str) -> Self {
+        self.assets = Some((String::from(dir), String::from(manifest)));
+        self
+    }
+
+    // Subscribe to delivery, read, echo and reaction events
+    pub fn with_event_hook(mut self, hook: Arc<dyn Fn(&BotUser) + Send + Sync>) -> Self {
+        self.event_hooks.push(hook);
+        self
+    }
+
     pub fn with_static_file(mut self, file: &str) -> Self{
         self.static_file = Some(file.to_string());
         self
@@ -118,9 +513,34 @@ impl BotMessenger {
 
     }
 
+    // Errors and warnings of the blocks, launch refuses to start on errors
+    pub fn validate(&self) -> Vec<Issue> {
+        lint(&self.blocks, &self.block_default, self.conf.get_welcome_back())
+    }
+
+    // Graph of the blocks in Graphviz DOT, to draw the bot in the docs
+    pub fn to_dot(&self) -> String {
+        to_dot(&self.blocks, &self.block_default)
+    }
+
+    // Same graph as a Mermaid flowchart
+    pub fn to_mermaid(&self) -> String {
+        to_mermaid(&self.blocks, &self.block_default)
+    }
+
     // Launch server rocket
     pub fn launch(&self) {
 
+        let issues = self.validate();
+        issues.iter().for_each(|x| match x.is_error() {
+            true => error!("{}", x),
+            false => warn!("{}", x),
+        });
+        let errors = issues.iter().filter(|x| x.is_error()).count();
+        if errors > 0 {
+            panic!("Flows have {} errors, not launching", errors);
+        }
+
         //let bot = self.clone();
 
         let config = Config::build(Environment::Development)
@@ -129,9 +549,29 @@ impl BotMessenger {
             .workers(*self.get_conf().get_workers())
             .finalize();
 
+        if self.get_conf().get_app_secret().is_empty() {
+            if !self.get_conf().get_allow_unsigned() {
+                panic!("No app secret set, use with_app_secret or allow_unsigned_webhooks");
+            }
+            warn!("No app secret set, webhook signatures will not be checked");
+        }
+
+        if let Some((dir, manifest)) = &self.assets {
+            if let Err(e) = upload_assets(dir, manifest, &self.client) {
+                warn!("Assets of {} not uploaded: {}", dir, e);
+            }
+        }
+
         let selfy = Arc::new(Mutex::new(self.clone()));
         //println!("Token {}",selfy.get_conf().get_token_fb_page());
 
+        if let Some(path) = &self.flow_file {
+            watch_flow(selfy.clone(), path.clone());
+        }
+        schedule(selfy.clone());
+        self.sessions.all().iter().for_each(|x| self.track_resume(x.get_sender()));
+        resume(selfy.clone(), self.resumes.clone());
+
         match config {
             Ok(e) => {
                 let route = format!("/{}",self.get_conf().get_uri());
@@ -153,6 +593,19 @@ impl BotMessenger {
         &self.conf
     }
 
+    // Send API client shared by the blocks
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    // Give the blocks a client matching the conf
+    fn update_client(&mut self) {
+        self.client.set_conf(&self.conf);
+        let client = self.client();
+        self.blocks.iter_mut().for_each(|x| x.set_client(client.clone()));
+        self.block_default.set_client(client);
+    }
+
     pub fn get_conf_mut(&mut self) -> &mut Conf {
         &mut self.conf
     }
@@ -160,6 +613,191 @@ impl BotMessenger {
     pub fn add_block(&mut self, block: Block){
         self.blocks.push(block);
     }
+
+    // Block by name, the default block included
+    pub fn get_block(&self, name: &str) -> Option<&Block> {
+        self.blocks.iter().chain(std::iter::once(&self.block_default)).find(|x| x.get_name() == name)
+    }
+
+    fn get_block_mut(&mut self, name: &str) -> Option<&mut Block> {
+        self.blocks.iter_mut().chain(std::iter::once(&mut self.block_default)).find(|x| x.get_name() == name)
+    }
+
+    // Ttl of the block the session is in, else the global one
+    fn ttl(&self, session: &Session) -> Option<Duration> {
+        session.get_block()
+            .and_then(|x| self.get_block(x))
+            .and_then(|x| x.get_ttl())
+            .or_else(|| self.conf.get_session_ttl())
+    }
+
+    // Send the follow ups due
+    pub fn send_follow_ups(&self) -> usize {
+        let (sent, failed) = send_follow_ups(&self.take_follow_ups(), &self.client());
+        self.put_back_follow_ups(failed);
+        sent
+    }
+
+    // Remove the follow ups due from the sessions, the window policy decides
+    // of the ones out of the 24h window when they are sent
+    fn take_follow_ups(&self) -> Vec<(BotUser, FollowUp)> {
+        let mut due = Vec::new();
+
+        for mut session in self.sessions.all() {
+            let follow_ups = session.take_due(now());
+            if follow_ups.is_empty() {
+                continue
+            }
+            self.sessions.set(session.clone());
+
+            let user = BotUser::new(session.get_sender(), Arc::new(MessagingMessage::new("")))
+                .with_vars(session.get_vars())
+                .with_received(session.get_received());
+            for follow_up in follow_ups {
+                due.push((user.clone(), follow_up));
+            }
+        }
+        due
+    }
+
+    // Schedule again the follow ups whose send failed on a transient error,
+    // except the ones the user answered since they were due
+    fn put_back_follow_ups(&self, failed: Vec<(BotUser, FollowUp)>) {
+        for (user, follow_up) in failed {
+            let mut session = match self.sessions.get(user.get_sender()) {
+                Some(e) => e,
+                None => continue,
+            };
+            if follow_up.is_cancel_on_reply() && session.get_received() >= follow_up.get_due() {
+                continue
+            }
+            session.schedule(follow_up);
+            self.sessions.set(session);
+        }
+    }
+
+    // Evict the sessions silent for longer than their ttl, a user parked in
+    // a block is kept as expired for the welcome back block when there is one
+    pub fn sweep(&self) -> usize {
+        let mut evicted = 0;
+
+        for mut session in self.sessions.all() {
+            // Kept until its follow ups are sent
+            if !session.get_follow_ups().is_empty() {
+                continue
+            }
+
+            let ttl = match (session.is_expired(), self.ttl(&session)) {
+                (true, _) => self.conf.get_session_ttl().unwrap_or(EXPIRED_KEEP),
+                (false, Some(e)) => e,
+                (false, None) => continue,
+            };
+            if !session.is_stale(ttl) {
+                continue
+            }
+
+            if session.get_block().is_some() && self.conf.get_welcome_back().is_some() {
+                session.expire();
+                self.sessions.set(session);
+            }
+            else {
+                self.sessions.remove(session.get_sender());
+                evicted += 1;
+            }
+        }
+
+        if evicted > 0 {
+            info!("{} stale sessions evicted", evicted);
+        }
+        evicted
+    }
+}
+
+// Evict the stale sessions and send the follow ups in the background, the
+// sends happen out of the lock so the webhooks don't wait for them
+fn schedule(bot: Arc<Mutex<BotMessenger>>) {
+    thread::spawn(move || loop {
+        thread::sleep(SCHEDULE_INTERVAL);
+
+        let (due, client) = match bot.lock() {
+            Ok(b) => {
+                b.sweep();
+                (b.take_follow_ups(), b.client())
+            },
+            Err(_) => break,
+        };
+        let (_, failed) = send_follow_ups(&due, &client);
+
+        if !failed.is_empty() {
+            match bot.lock() {
+                Ok(b) => b.put_back_follow_ups(failed),
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+// Go on with the paused PipeBoxes, the bot is only locked when one is due
+fn resume(bot: Arc<Mutex<BotMessenger>>, resumes: Arc<Mutex<HashMap<String,u64>>>) {
+    thread::spawn(move || loop {
+        thread::sleep(RESUME_INTERVAL);
+
+        let now = now_millis();
+        if !resumes.lock().unwrap().values().any(|x| *x <= now) {
+            continue
+        }
+        match bot.lock() {
+            Ok(mut b) => {
+                b.resume_due();
+            },
+            Err(_) => break,
+        }
+    });
+}
+
+// Count of the follow ups sent, and the ones to try again at the next round
+fn send_follow_ups(due: &[(BotUser, FollowUp)], client: &Client) -> (usize, Vec<(BotUser, FollowUp)>) {
+    let mut sent = 0;
+    let mut failed = Vec::new();
+    for (user, follow_up) in due {
+        let message = Message::new(Some(String::from(follow_up.get_text())), None, None)
+            .with_messaging_type(api::MessagingType::UPDATE);
+
+        match message.send(user, client) {
+            Ok(_) => sent += 1,
+            Err(e) if e.is_retryable() => {
+                warn!("Follow up to {} kept for a retry: {}", user.get_sender(), e);
+                failed.push((user.clone(), follow_up.clone()));
+            },
+            Err(e) => warn!("Follow up to {} not sent: {}", user.get_sender(), e),
+        }
+    }
+    (sent, failed)
+}
+
+// Reload the bot when the modification time of the flow file changes
+fn watch_flow(bot: Arc<Mutex<BotMessenger>>, path: String) {
+    let modified = |path: &str| fs::metadata(path).and_then(|x| x.modified()).ok();
+    let mut last: Option<SystemTime> = modified(&path);
+
+    thread::spawn(move || loop {
+        thread::sleep(FLOW_WATCH_INTERVAL);
+
+        let current = modified(&path);
+        if current.is_none() || current == last {
+            continue
+        }
+        last = current;
+
+        match bot.lock() {
+            Ok(mut b) => {
+                if let Err(e) = b.reload() {
+                    warn!("Flows of {} not reloaded, the old ones keep running: {}", path, e);
+                }
+            },
+            Err(_) => break,
+        }
+    });
 }
 
 #[derive(FromForm)]
@@ -188,18 +826,75 @@ fn root_connection(bot: State<Arc<Mutex<BotMessenger>>>, hub: Form<FbForm>) -> S
     }
 }
 
-#[post("/" ,format = "json", data = "<user>")]
-fn root_message(bot: State<Arc<Mutex<BotMessenger>>> ,user: Json<BotUser>) -> &'static str {
+// Signature sent by Facebook with every webhook POST
+struct HubSignature(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for HubSignature {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("X-Hub-Signature-256") {
+            Some(e) => Outcome::Success(HubSignature(e.to_string())),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Webhook POSTs must carry a valid signature, unless the bot opted out
+// of the check and has no secret
+fn check_signature(conf: &Conf, signature: Option<&str>, body: &[u8]) -> Result<(), Status> {
+    let secret = conf.get_app_secret();
+    if secret.is_empty() {
+        if conf.get_allow_unsigned() {
+            return Ok(())
+        }
+        warn!("Reject webhook: no app secret to check the signature");
+        return Err(Status::Forbidden)
+    }
+
+    match signature {
+        Some(s) if verify_signature(secret, body, s) => Ok(()),
+        Some(_) => {
+            warn!("Reject webhook: signature doesn't match");
+            Err(Status::Forbidden)
+        },
+        None => {
+            warn!("Reject webhook: missing X-Hub-Signature-256 header");
+            Err(Status::Unauthorized)
+        },
+    }
+}
+
+#[post("/" ,format = "json", data = "<data>")]
+fn root_message(bot: State<Arc<Mutex<BotMessenger>>> ,signature: Option<HubSignature> ,data: Data) -> Result<&'static str, Status> {
+    let mut body = String::new();
+    if let Err(e) = data.open().take(BODY_LIMIT).read_to_string(&mut body) {
+        warn!("Can't read the webhook body: {}", e);
+        return Err(Status::BadRequest)
+    }
+
     let bot = bot.clone();
     let bot = &mut bot.lock();
 
     if let Ok(b) = bot {
-       info!("New user: {}",*user);
-        b.add_user(user.clone());
-        "ok" 
+        check_signature(b.get_conf(), signature.as_ref().map(|x| x.0.as_str()), body.as_bytes())?;
+
+        let webhook: Webhook = match serde_json::from_str(&body) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Can't parse the webhook body: {}", e);
+                return Err(Status::BadRequest)
+            }
+        };
+
+        for event in webhook.get_events() {
+            info!("New user: {}",event.get_user());
+            b.add_user(event.get_user().clone());
+        }
+        Ok("ok")
     }
     else {
-        "Don't understand ?"
+        Ok("Don't understand ?")
     }
     
 }
@@ -212,7 +907,7 @@ fn get_basic() -> &'static str {
 #[cfg(test)]
 mod tests {
 
-    use crate::BotMessenger;
+    use crate::{BotMessenger, check_signature};
     use crate::utils;
     use crate::api;
 
@@ -224,6 +919,206 @@ mod tests {
     use api::card::CardGeneric;
     use api::card::CardButtons;
     use api::button::Button;
+    use api::{MessageTag, WindowPolicy};
+    use api::retry::RetryPolicy;
+    use api::transport::{RecordingTransport, user};
+    use utils::{Conf, BotUser, MessagingMessage};
+    use utils::session::{FollowUp, now};
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reload_flows() {
+        let path = std::env::temp_dir().join(format!("botMessenger-flow-{}.yaml", std::process::id()));
+        let path = path.to_str().unwrap();
+        let flow = |blocks: &str| format!("default:\n  name: default\n  boxes:\n    - text: Sorry\nblocks:\n{}", blocks);
+        let hello = "  - name: Hello\n    boxes:\n      - text: Your name ?\n      - capture: name\n";
+        let bye = "  - name: Bye\n    boxes:\n      - text: Why ?\n      - capture: why\n";
+        fs::write(path, flow(&format!("{}{}", hello, bye))).unwrap();
+
+        let mut bot = BotMessenger::from_flow_file(path).unwrap()
+            .with_transport(Arc::new(RecordingTransport::new()))
+            .with_token_fb("token");
+        bot.add_user(user("Hello"));
+        bot.add_user(BotUser::new("2", Arc::new(MessagingMessage::new("Bye"))));
+
+        // A broken file keeps the old flows
+        fs::write(path, "blocks: [").unwrap();
+        assert!(bot.reload().is_err());
+        assert_eq!(bot.blocks.len(), 2);
+
+        fs::write(path, flow(hello)).unwrap();
+        bot.reload().unwrap();
+        assert_eq!(bot.blocks.len(), 1);
+
+        let session = bot.sessions.get("1").unwrap();
+        assert_eq!((session.get_block(), session.get_index()), (Some("Hello"), 1));
+        let session = bot.sessions.get("2").unwrap();
+        assert_eq!((session.get_block(), session.get_index()), (Some("default"), 0));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn session_expiry() {
+        let transport = Arc::new(RecordingTransport::new());
+        let mut bot = BotMessenger::new()
+            .with_transport(transport.clone())
+            .with_token_fb("token")
+            .with_session_ttl(Duration::from_secs(3600))
+            .with_welcome_back("Back")
+            .block(Block::new("Order")
+                .ttl(Duration::from_secs(60))
+                .cartBox(CartBox::new()
+                    .text("Which size ?"))
+                .cartBox(CartBox::new()
+                    .capture("size")))
+            .block(Block::new("Back")
+                .cartBox(CartBox::new()
+                    .text("Welcome back")));
+
+        bot.add_user(user("Order"));
+        let mut session = bot.sessions.get("1").unwrap();
+        session.set_updated(session.get_updated() - 120);
+        bot.sessions.set(session);
+
+        // The sweeper keeps the user for the welcome back
+        assert_eq!(bot.sweep(), 0);
+        assert!(bot.sessions.get("1").unwrap().is_expired());
+
+        bot.add_user(user("XL"));
+        assert_eq!(transport.get_bodies().last().unwrap()["message"]["text"], "Welcome back");
+        let session = bot.sessions.get("1").unwrap();
+        assert!(!session.is_expired());
+        assert_eq!(session.get_var("size"), None);
+
+        // Out of any block the global ttl evicts
+        let mut session = bot.sessions.get("1").unwrap();
+        session.set_updated(session.get_updated() - 7200);
+        bot.sessions.set(session);
+        assert_eq!(bot.sweep(), 1);
+        assert!(bot.sessions.get("1").is_none());
+    }
+
+    #[test]
+    fn follow_ups() {
+        let transport = Arc::new(RecordingTransport::new());
+        let mut bot = BotMessenger::new()
+            .with_transport(transport.clone())
+            .with_token_fb("token")
+            .with_retry(RetryPolicy::none())
+            .block(Block::new("Cart")
+                .cartBox(CartBox::new()
+                    .text("Your cart is waiting")
+                    .follow_up(Duration::from_secs(0), "Still want your {{item|order}} ?")
+                    .reminder(Duration::from_secs(3600), "Sale ends soon"))
+                .cartBox(CartBox::new()
+                    .capture("answer")));
+
+        bot.add_user(user("Cart"));
+        transport.clear();
+        assert_eq!(bot.send_follow_ups(), 1);
+        assert_eq!(transport.get_bodies().last().unwrap(), &json!({
+            "messaging_type": "UPDATE",
+            "recipient": {"id": "1"},
+            "message": {"text": "Still want your order ?", "quick_replies": null},
+        }));
+        assert_eq!(bot.send_follow_ups(), 0);
+
+        // Going through the box again replaces its reminder, the reply
+        // cancels the nudge but not the reminder
+        bot.add_user(user("Cart"));
+        bot.add_user(user("no"));
+        assert_eq!(bot.send_follow_ups(), 0);
+        let session = bot.sessions.get("1").unwrap();
+        let texts: Vec<&str> = session.get_follow_ups().iter().map(|x| x.get_text()).collect();
+        assert_eq!(texts, vec!["Sale ends soon"]);
+
+        // A transient failure keeps it for the next round
+        let mut session = bot.sessions.get("1").unwrap();
+        session.schedule(FollowUp::new(Duration::from_secs(0), "Back in stock", false));
+        bot.sessions.set(session);
+        transport.respond(500, r#"{"error":{"message":"down","type":"OAuthException","code":2,"fbtrace_id":"a"}}"#);
+        assert_eq!(bot.send_follow_ups(), 0);
+        assert_eq!(bot.send_follow_ups(), 1);
+
+        // Refused out of the 24h window without a tag
+        let mut session = bot.sessions.get("1").unwrap();
+        session.schedule(FollowUp::new(Duration::from_secs(0), "Too late", false));
+        session.set_received(session.get_received() - 25 * 3600);
+        bot.sessions.set(session);
+        assert_eq!(bot.send_follow_ups(), 0);
+    }
+
+    #[test]
+    fn typing_resumed() {
+        let transport = Arc::new(RecordingTransport::new());
+        let mut bot = BotMessenger::new()
+            .with_transport(transport.clone())
+            .with_token_fb("token")
+            .block(Block::new("Hello")
+                .cartBox(CartBox::new()
+                    .text("Hi there")
+                    .typing(Duration::from_millis(50))
+                    .branch("Menu", Arc::new(|_, _| true))))
+            .block(Block::new("Menu")
+                .cartBox(CartBox::new()
+                    .text("What do you want ?")));
+
+        bot.add_user(user("Hello"));
+        assert_eq!(bot.resume_due(), 0);
+        assert_eq!(transport.get_bodies().last().unwrap()["sender_action"], "typing_on");
+
+        // The box goes on after its delay and follows its branch
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(bot.resume_due(), 1);
+        let texts: Vec<_> = transport.get_bodies().iter().filter_map(|x| x["message"]["text"].as_str()).map(String::from).collect();
+        assert_eq!(texts, vec!["Hi there", "What do you want ?"]);
+        assert_eq!(bot.resume_due(), 0);
+    }
+
+    #[test]
+    fn window_policy_reaches_blocks() {
+        let transport = Arc::new(RecordingTransport::new());
+        let policy = WindowPolicy::TAG(MessageTag::ACCOUNTUPDATE);
+        let mut bot = BotMessenger::new()
+            .with_transport(transport.clone())
+            .with_token_fb("token")
+            .block(Block::new("Order")
+                .cartBox(CartBox::new()
+                    .text("Your order shipped")))
+            .with_window_policy(policy.clone());
+        assert_eq!(bot.client().get_window_policy(), &policy);
+
+        bot.add_user(user("Order").with_received(now() - 25 * 3600));
+        assert_eq!(transport.get_bodies().last().unwrap()["tag"], "ACCOUNT_UPDATE");
+    }
+
+    #[test]
+    fn implicit_default_block() {
+        let mut bot = BotMessenger::new()
+            .with_transport(Arc::new(RecordingTransport::new()))
+            .with_token_fb("token");
+        bot.add_user(user("Hi"));
+        assert!(bot.sessions.get("1").is_some());
+    }
+
+    #[test]
+    fn unsigned_webhooks() {
+        let body = br#"{"object":"page","entry":[]}"#;
+
+        // No secret rejects everything until the bot opts out
+        let mut conf = Conf::default();
+        assert!(check_signature(&conf, None, body).is_err());
+        conf.set_allow_unsigned(true);
+        assert!(check_signature(&conf, None, body).is_ok());
+
+        // With a secret the opt out doesn't skip the check
+        conf.set_app_secret("MamaGuriba");
+        assert!(check_signature(&conf, None, body).is_err());
+        assert!(check_signature(&conf, Some("sha256=00"), body).is_err());
+    }
 
     #[test]
     fn it_works() { 
@@ -259,6 +1154,7 @@ mod tests {
                     .text("New start user")))
             .with_token_fb(&std::env::var("TOKEN_FB").unwrap())
             .with_token_wh("MamaGuriba")
+            .allow_unsigned_webhooks()
             .launch();
     }
 }
@@ -1,15 +1,26 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use super::{BotUser, PipeBox, PipeStatus};
+use super::validator::Validator;
+use super::trigger::Trigger;
+use super::lint::{Level, Outline};
+use super::session::{Session, SessionStore, MemoryStore, FollowUp, now_millis};
 use log::{info, warn};
 use crate::api::{button::*, card::*};
-use crate::api::{ApiMessage, Message};
+use crate::api::{ApiMessage, Message, MessageTag, SenderAction};
+use crate::api::client::Client;
+use crate::api::media::{Media, MediaType};
 
 
 #[derive(Clone)]
 pub struct Block{
     name: String,
-    token: String,
-    childs: Arc<Vec<(BotUser,usize)>>,
+    client: Client,
+    sessions: Arc<dyn SessionStore>,
+    triggers: Vec<Trigger>,
+    utterances: Vec<String>,
+    ttl: Option<Duration>,
     pipe: Vec<Arc<dyn PipeBox + Send + Sync>>,
 }
 
@@ -17,8 +28,11 @@ impl Default for Block {
     fn default() -> Self {
         Block{
             name: String::from("Hello"),
-            token: String::from(""),
-            childs: Arc::new(Vec::new()),
+            client: Client::default(),
+            sessions: Arc::new(MemoryStore::new()),
+            triggers: Vec::new(),
+            utterances: Vec::new(),
+            ttl: None,
             pipe: Vec::new(),
         }
     }
@@ -33,78 +47,146 @@ impl Block {
         block
     }
 
-    // Rooting user
-    pub fn root(&mut self ,user: &BotUser) {
+    // Rooting user, give back the block to jump to on a GOTO
+    pub fn root(&mut self ,user: &BotUser) -> Option<String> {
         let find = self.find(user);
 
         match find {
             true => {
-                self.consume(user)
+                self.consume(user, true)
             },
             false => {
-
-                (*Arc::make_mut(&mut self.childs)).push((user.clone(),0));
-                self.consume(user);
+                // A new block starts at the first PipeBox, the variables stay
+                let mut session = self.sessions.get(user.get_sender())
+                    .unwrap_or_else(|| Session::new(user.get_sender()));
+                session.enter(&self.name, 0);
+
+                // Like on a jump, a first PipeBox waiting for an answer gets
+                // the next message rather than the one that started the block
+                match self.pipe.first().map(|x| x.internal_state()) {
+                    Some(PipeStatus::NEXT) | None => {
+                        self.sessions.set(session);
+                        self.consume(user, true)
+                    },
+                    Some(_) => {
+                        session.touch();
+                        self.sessions.set(session);
+                        None
+                    }
+                }
             }
         }
     }
 
-    // Consume the PipeBox for the user
-    fn consume(&mut self ,user: &BotUser) {
+    // Go on after a GOTO into this block, a PipeBox waiting for an answer
+    // gets the next message instead of this one
+    pub fn jump(&mut self ,user: &BotUser) -> Option<String> {
+        let session = self.get_session(user)?;
 
-        let value = match (*Arc::make_mut(&mut self.childs)).iter_mut().enumerate().find(|x| {x.1.0 == *user}) {
-            Some(x) => {
-                match self.pipe[x.1.1].consume(user, &self.token) {
-                    PipeStatus::NEXT => {
-                        x.1.1 = x.1.1 + 1;
-                        if x.1.1 >= self.pipe.len() {
-                            Some(x.0)
-                        }
-                        else {
-                            match self.pipe[x.1.1].internal_state() {
-                                PipeStatus::NEXT => {
-                                    self.consume(user);
-                                    None
-                                }
-                                _ => {
-                                    None
-                                }
-                            }
-                        }
-                    },
-                    PipeStatus::REPLAY => {
-                        x.1.1 = 0;
-                        None
-                    },
-                    PipeStatus::RESTART => {
-                        x.1.1 = 0;
-                        None
-                    },
-                }
-            }
+        match self.pipe.get(session.get_index()).map(|x| x.internal_state()) {
+            Some(PipeStatus::NEXT) | None => self.consume(user, false),
+            Some(_) => None,
+        }
+    }
+
+    // Go on with the PipeBox of the user once its delay is over
+    pub fn resume(&mut self ,user: &BotUser) -> Option<String> {
+        let session = self.get_session(user)?;
+
+        match session.get_resume() {
+            Some(e) if e <= now_millis() => self.consume(user, false),
+            _ => None,
+        }
+    }
+
+    // Consume the PipeBox for the user, replied when it comes from a message
+    // of the user rather than a jump or a resume
+    fn consume(&mut self ,user: &BotUser, replied: bool) -> Option<String> {
+
+        let mut session = match self.get_session(user) {
+            Some(e) => e,
             None => {
                 warn!("Don't match with any childs");
-                None
+                return None
             }
         };
+        let mut goto = None;
 
-        match value {
-            Some(e) => {
-                (*Arc::make_mut(&mut self.childs)).remove(e);
-            },
-            None => {}
+        loop {
+            if session.get_index() >= self.pipe.len() {
+                session.leave();
+                break
+            }
+
+            // The PipeBox sees the variables captured so far
+            let current = user.with_vars(session.get_vars());
+            let status = self.pipe[session.get_index()].consume(&current, &self.client, &mut session);
+            if !matches!(status, PipeStatus::DELAY(_)) {
+                session.clear_pause();
+            }
+
+            match status {
+                PipeStatus::NEXT => {
+                    session.set_index(session.get_index() + 1);
+                    if session.get_index() >= self.pipe.len() {
+                        session.leave();
+                        break
+                    }
+
+                    match self.pipe[session.get_index()].internal_state() {
+                        PipeStatus::NEXT => continue,
+                        _ => break,
+                    }
+                },
+                PipeStatus::REPLAY => {
+                    session.set_index(0);
+                    break
+                },
+                PipeStatus::RESTART => {
+                    session.set_index(0);
+                    break
+                },
+                // The user stays on this PipeBox until the next message
+                PipeStatus::WAIT => {
+                    break
+                },
+                PipeStatus::GOTO(block, index) => {
+                    info!("Jump to {} at {}", block, index);
+                    session.enter(&block, index);
+                    goto = Some(block);
+                    break
+                },
+                // The bot goes on with this PipeBox later, see BotMessenger::resume_due
+                PipeStatus::DELAY(delay) => {
+                    info!("Resume {} in {:?}", user.get_sender(), delay);
+                    session.pause(delay);
+                    break
+                },
+            }
         }
 
+        if replied {
+            session.touch();
+        }
+        self.sessions.set(session);
+        goto
     }
 
-    // Setter
     pub fn set_name(&mut self, name: &str) -> &mut Self{
         self.name = String::from(name);
         self
     }
 
     pub fn set_token(&mut self, token: &str) {
-        self.token = String::from(token);
+        self.client.set_token(token);
+    }
+
+    pub fn set_client(&mut self, client: Client) {
+        self.client = client;
+    }
+
+    pub fn set_store(&mut self, sessions: Arc<dyn SessionStore>) {
+        self.sessions = sessions;
     }
 
     pub fn get_name(&self) -> &str {
@@ -120,60 +202,161 @@ impl Block {
         self
     }
 
-    pub fn find(&self,user: &BotUser) -> bool {
-        match self.childs.iter().find(|x| {
-            x.0.get_sender() == user.get_sender()
-        }) {
-            Some(_) => {
-                return true
-            }
-            None => {
-                return false
-            }
-        }
+    // Other messages than the name starting the block
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        self.triggers.push(trigger);
+        self
     }
 
-    pub fn find_mut(&mut self,user: &BotUser) -> Option<&mut (BotUser,usize)> {
-        (*Arc::make_mut(&mut self.childs)).iter_mut().find(|x| {
-            x.0.get_sender() == user.get_sender()
-        })
+    pub fn get_triggers(&self) -> &[Trigger] {
+        &self.triggers
     }
 
-    pub fn remove_child(&mut self,user: &BotUser) {
-        let child = (*Arc::make_mut(&mut self.childs)).iter_mut().enumerate().find(|x| {
-            x.1.0.get_sender() == user.get_sender()
-        });
+    // Example of a message for this block, used to train the intent recognizer
+    pub fn utterance(mut self, text: &str) -> Self {
+        self.utterances.push(String::from(text));
+        self
+    }
 
-        let child = match child {
-            Some(e) => Some(e.0),
-            None => None,
-        };
+    pub fn get_utterances(&self) -> &[String] {
+        &self.utterances
+    }
+
+    // Time a user may stay silent in this block, else the global one of the conf
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
 
-        match child {
-            Some(e) => {(*Arc::make_mut(&mut self.childs)).remove(e);},
-            None => {},
+    pub fn get_ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    // Strongest trigger matching the message with its captured variables
+    pub fn matching(&self, user: &BotUser) -> Option<(usize, HashMap<String,String>)> {
+        if user.get_message().message() == self.name {
+            return Some((0, HashMap::new()))
         }
 
+        self.triggers.iter()
+            .filter_map(|x| x.matches(user).map(|vars| (x.priority(), vars)))
+            .min_by_key(|x| x.0)
+    }
+
+    pub fn find(&self,user: &BotUser) -> bool {
+        self.get_session(user).is_some()
     }
+
+    // Session of the user while they are in this block
+    pub fn get_session(&self,user: &BotUser) -> Option<Session> {
+        match self.sessions.get(user.get_sender()) {
+            Some(e) if e.get_block() == Some(self.name.as_str()) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn remove_child(&mut self,user: &BotUser) {
+        if let Some(mut session) = self.get_session(user) {
+            session.leave();
+            self.sessions.set(session);
+        }
+    }
+}
+
+// Limits of the Send API
+const MAX_TEXT: usize = 2000;
+const MAX_QUICK_REPLIES: usize = 13;
+const MAX_CARDS: usize = 10;
+
+// Keep the next answer of the user in a session variable
+#[derive(Clone)]
+struct Capture {
+    name: String,
+    validator: Option<Validator>,
+    reprompt: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct CartBox {
     function_controle: Arc<dyn Fn(&BotUser) -> Option<&BotUser> + Send + Sync>,
     internal_state: PipeStatus,
+    typing: Option<Duration>,
+    capture: Option<Capture>,
+    branches: Vec<(Arc<dyn Fn(&BotUser, &Session) -> bool + Send + Sync>, String)>,
+    follow_ups: Vec<(Duration, String, bool)>,
+    tag: Option<MessageTag>,
 
     text: Option<String>,
     button: Option<Vec<Button>>,
     cards: Option<Vec<Arc<dyn Card>>>,
+    media: Option<Media>,
 }
 
 impl PipeBox for CartBox{
-    fn consume(&self,message: &BotUser, token: &str) -> PipeStatus {
+    fn consume(&self,message: &BotUser, client: &Client, session: &mut Session) -> PipeStatus {
         info!("Consume in the block the pipebox");
+
+        // Back from a delay the answer is already captured
+        let resumed = session.get_resume().is_some();
+        let captured;
+        let message = match &self.capture {
+            Some(capture) if !resumed => {
+                let answer = message.get_message().message().trim().to_string();
+                let valid = capture.validator.as_ref().map(|x| x.is_valid(&answer)).unwrap_or(true);
+
+                if !valid {
+                    info!("Answer for {} doesn't validate", capture.name);
+                    if let Some(reprompt) = &capture.reprompt {
+                        let reprompt = Message::new(Some(reprompt.clone()),None,None);
+                        if let Err(e) = reprompt.send(message, &client.without_retry()) {
+                            warn!("Reprompt not sent: {}", e);
+                        }
+                    }
+                    return PipeStatus::WAIT
+                }
+
+                session.set_var(&capture.name, &answer);
+                captured = message.with_vars(session.get_vars());
+
+                // A capture without content goes on silently
+                if self.is_empty() {
+                    return self.done(&captured, session)
+                }
+                &captured
+            },
+            _ => message,
+        };
+
         match (self.function_controle)(message) {
             Some(e) => {
-                self.build().send(e,token);
-                PipeStatus::NEXT
+                // A box made only of branches or follow ups sends nothing
+                if self.is_empty() && (!self.branches.is_empty() || !self.follow_ups.is_empty()) {
+                    return self.done(e, session)
+                }
+
+                // The typing indicator shows while the bot waits, not under its lock
+                if let (Some(typing), false) = (self.typing, resumed) {
+                    if let Err(e) = SenderAction::TYPINGON.send(e, &client.without_retry()) {
+                        warn!("Typing indicator not sent: {}", e);
+                    }
+                    return PipeStatus::DELAY(typing)
+                }
+
+                // One try at a time, a transient failure comes back after the
+                // backoff and the user stays on the box until it's sent
+                match self.build().send(e, &client.without_retry()) {
+                    Ok(_) => self.done(e, session),
+                    Err(error) => {
+                        let attempts = session.get_attempts() + 1;
+                        if error.is_retryable() && attempts < client.get_retry().get_max_attempts() {
+                            warn!("Message to {} retried: {}", e.get_sender(), error);
+                            session.set_attempts(attempts);
+                            return PipeStatus::DELAY(client.get_retry().delay(attempts))
+                        }
+                        warn!("Message not sent, the user stays on this box: {}", error);
+                        PipeStatus::WAIT
+                    }
+                }
             }
             None => {
                 PipeStatus::REPLAY
@@ -184,6 +367,64 @@ impl PipeBox for CartBox{
     fn internal_state(&self) -> &PipeStatus {
         &self.internal_state
     }
+
+    fn outline(&self) -> Outline {
+        let label = match (&self.capture, &self.text, &self.cards, &self.media) {
+            (_, Some(text), _, _) => text.clone(),
+            (_, _, Some(cards), _) => format!("{} cards", cards.len()),
+            (_, _, _, Some(Media::URL(kind, url))) => format!("{} {}", kind, url),
+            (_, _, _, Some(Media::ASSET(name))) => format!("asset {}", name),
+            (Some(capture), _, _, _) => format!("capture {}", capture.name),
+            _ => String::new(),
+        };
+        let mut outline = Outline::new(&label);
+
+        let buttons = self.button.iter().flatten().cloned()
+            .chain(self.cards.iter().flatten().flat_map(|x| x.get_buttons()));
+        for button in buttons {
+            if let Button::PAYLOAD(_, payload) | Button::QUICKPAYLOAD(_, payload) = button {
+                outline = outline.payload(&payload);
+            }
+        }
+        for (_, block) in &self.branches {
+            outline = outline.goto(block);
+        }
+
+        if self.is_empty() && self.capture.is_none() && self.branches.is_empty() && self.follow_ups.is_empty() {
+            outline = outline.issue(Level::ERROR, "box sends nothing, it would send \"Basic Text\"");
+        }
+        if self.text.as_ref().map(|x| x.chars().count() > MAX_TEXT).unwrap_or(false) {
+            outline = outline.issue(Level::ERROR, &format!("text is longer than {} characters", MAX_TEXT));
+        }
+        if self.text.is_some() && (self.cards.is_some() || self.media.is_some()) {
+            outline = outline.issue(Level::WARNING, "only the text is sent, the cards and media are ignored");
+        }
+        if self.cards.is_some() && self.media.is_some() {
+            outline = outline.issue(Level::WARNING, "only the cards are sent, the media is ignored");
+        }
+        if self.cards.is_some() && self.button.is_some() {
+            outline = outline.issue(Level::WARNING, "quick replies are not sent with cards");
+        }
+        if self.button.as_ref().map(|x| x.len() > MAX_QUICK_REPLIES).unwrap_or(false) {
+            outline = outline.issue(Level::ERROR, &format!("more than {} quick replies", MAX_QUICK_REPLIES));
+        }
+
+        if let Some(cards) = &self.cards {
+            if cards.iter().any(|x| x.typed() != cards[0].typed()) {
+                outline = outline.issue(Level::WARNING, &format!("cards of another kind than the first ({}) are broken", cards[0].typed()));
+            }
+            if cards[0].typed() == "generic" && cards.len() > MAX_CARDS {
+                outline = outline.issue(Level::ERROR, &format!("more than {} generic cards", MAX_CARDS));
+            }
+            if cards[0].typed() == "buttons" && cards.len() > 1 {
+                outline = outline.issue(Level::WARNING, "only the first buttons card is sent");
+            }
+            for issue in cards.iter().flat_map(|x| x.lint()) {
+                outline = outline.issue(Level::ERROR, &issue);
+            }
+        }
+        outline
+    }
 }
 
 impl CartBox {
@@ -192,10 +433,16 @@ impl CartBox {
         CartBox{
             function_controle: function_controle,
             internal_state: PipeStatus::NEXT,
+            typing: None,
+            capture: None,
+            branches: Vec::new(),
+            follow_ups: Vec::new(),
+            tag: None,
 
             text: None,
             button: None,
             cards: None,
+            media: None,
         }
     }
 
@@ -230,6 +477,91 @@ impl CartBox {
         self
     }
 
+    // Show typing_on during the duration before sending the content
+    pub fn typing(mut self, duration: Duration) -> Self {
+        self.typing = Some(duration);
+        self
+    }
+
+    // Lets the message out of the 24h window, for what the tag covers
+    pub fn tag(mut self, tag: MessageTag) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    // Media sent as a reusable attachment, the upload is done once by url
+    pub fn image(mut self, url: &str) -> Self {
+        self.media = Some(Media::URL(MediaType::IMAGE, String::from(url)));
+        self
+    }
+
+    pub fn video(mut self, url: &str) -> Self {
+        self.media = Some(Media::URL(MediaType::VIDEO, String::from(url)));
+        self
+    }
+
+    pub fn audio(mut self, url: &str) -> Self {
+        self.media = Some(Media::URL(MediaType::AUDIO, String::from(url)));
+        self
+    }
+
+    pub fn file(mut self, url: &str) -> Self {
+        self.media = Some(Media::URL(MediaType::FILE, String::from(url)));
+        self
+    }
+
+    // Asset uploaded at startup, by its logical name like "logo"
+    pub fn asset(mut self, name: &str) -> Self {
+        self.media = Some(Media::ASSET(String::from(name)));
+        self
+    }
+
+    // Wait for the next message and keep it in the session under this name
+    pub fn capture(mut self, name: &str) -> Self {
+        self.capture = Some(Capture{
+            name: String::from(name),
+            validator: None,
+            reprompt: None,
+        });
+        self.internal_state = PipeStatus::WAIT;
+        self
+    }
+
+    // Refuse the captured answer when it doesn't validate
+    pub fn validate(mut self, validator: Validator) -> Self {
+        if let Some(e) = &mut self.capture {
+            e.validator = Some(validator);
+        }
+        self
+    }
+
+    // Sent when the captured answer doesn't validate
+    pub fn reprompt(mut self, text: &str) -> Self {
+        if let Some(e) = &mut self.capture {
+            e.reprompt = Some(String::from(text));
+        }
+        self
+    }
+
+    // Jump to the start of the block when the predicate holds, the first
+    // matching branch wins and without any the block goes on
+    pub fn branch(mut self, block: &str, predicate: Arc<dyn Fn(&BotUser, &Session) -> bool + Send + Sync>) -> Self {
+        self.branches.push((predicate, String::from(block)));
+        self
+    }
+
+    // Nudge sent after the delay unless the user writes first
+    pub fn follow_up(mut self, delay: Duration, text: &str) -> Self {
+        self.follow_ups.push((delay, String::from(text), true));
+        self
+    }
+
+    // Sent after the delay even if the user writes in between
+    pub fn reminder(mut self, delay: Duration, text: &str) -> Self {
+        self.follow_ups.push((delay, String::from(text), false));
+        self
+    }
+
     pub fn with_func_ctrl(&mut self,func: Arc<dyn Fn(&BotUser) -> Option<&BotUser> + Send + Sync>){
         self.function_controle = func;
     }
@@ -238,22 +570,254 @@ impl CartBox {
         self.internal_state = state;
     }
 
-    fn build(&self) -> Box<dyn ApiMessage> {
+    // Nothing to send, build would fall back on a basic text
+    fn is_empty(&self) -> bool {
+        self.text.is_none() && self.cards.is_none() && self.media.is_none()
+    }
+
+    // The box went through, schedule its follow ups in place of the ones of
+    // an earlier pass and pick the next step
+    fn done(&self, user: &BotUser, session: &mut Session) -> PipeStatus {
+        if !self.follow_ups.is_empty() {
+            let key = format!("{}[{}]", session.get_block().unwrap_or_default(), session.get_index());
+            session.remove_follow_ups(&key);
+            for (delay, text, cancel_on_reply) in &self.follow_ups {
+                session.schedule(FollowUp::new(*delay, text, *cancel_on_reply).with_key(&key));
+            }
+        }
+        self.branch_status(user, session)
+    }
+
+    fn branch_status(&self, user: &BotUser, session: &Session) -> PipeStatus {
+        match self.branches.iter().find(|x| (x.0)(user, session)) {
+            Some(e) => PipeStatus::GOTO(e.1.clone(), 0),
+            None => PipeStatus::NEXT,
+        }
+    }
+
+    fn build(&self) -> Message {
         let text = &self.text;
         let button = &self.button;
         let cards = &self.cards;
+        let media = &self.media;
 
-        if text.is_some() && button.is_some() {
-            Box::new(Message::new(Some(text.clone().unwrap()),Some(button.clone().unwrap()),None))
+        let message = if text.is_some() && button.is_some() {
+            Message::new(Some(text.clone().unwrap()),Some(button.clone().unwrap()),None)
         }
         else if text.is_some() && button.is_none() {
-            Box::new(Message::new(Some(text.clone().unwrap()),None,None))
+            Message::new(Some(text.clone().unwrap()),None,None)
         }
         else if cards.is_some() {
-            Box::new(Message::new(None,None,cards.clone()))
+            Message::new(None,None,cards.clone())
+        }
+        else if let Some(e) = media {
+            Message::new(None,button.clone(),None).with_media(e.clone())
         }
         else {
-            Box::new(Message::new(Some(String::from("Basic Text")),None,None))
+            Message::new(Some(String::from("Basic Text")),None,None)
+        };
+
+        match &self.tag {
+            Some(tag) => message.with_tag(tag.clone()),
+            None => message,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Block, CartBox};
+    use crate::api::transport::{recording_client, user};
+    use crate::api::button::Button;
+    use crate::api::card::CardButtons;
+    use crate::utils::{BotUser, MessagingMessage};
+    use crate::utils::validator::Validator;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn block_payloads() {
+        let (client, transport) = recording_client();
+
+        let mut block = Block::new("Hello")
+            .cartBox(CartBox::new()
+                .text("Hello new user")
+                .button_postback("Push", "Hello"))
+            .cartBox(CartBox::new()
+                .card(CardButtons::new("Can you choose !")
+                    .button(Button::new_button_pb("not me !", "Hello"))));
+        block.set_client(client);
+
+        block.root(&user("Hello"));
+
+        let bodies = transport.get_bodies();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], json!({
+            "messaging_type": "RESPONSE",
+            "recipient": {"id": "1"},
+            "message": {
+                "text": "Hello new user",
+                "quick_replies": [{"content_type": "text", "title": "Push", "payload": "Hello"}],
+            }
+        }));
+        assert_eq!(bodies[1]["message"]["attachment"]["payload"], json!({
+            "template_type": "button",
+            "text": "Can you choose !",
+            "buttons": [{"type": "postback", "title": "not me !", "payload": "Hello"}],
+        }));
+    }
+
+    #[test]
+    fn capture_with_reprompt() {
+        let (client, transport) = recording_client();
+
+        let mut block = Block::new("Signup")
+            .cartBox(CartBox::new()
+                .text("Your email ?"))
+            .cartBox(CartBox::new()
+                .capture("email")
+                .validate(Validator::EMAIL)
+                .reprompt("It's not an email"))
+            .cartBox(CartBox::new()
+                .text("Thanks"));
+        block.set_client(client);
+
+        block.root(&user("Signup"));
+        block.root(&user("bear"));
+        assert_eq!(block.get_session(&user("")).unwrap().get_index(), 1);
+
+        block.root(&user("bear@forest.fr"));
+        assert!(block.get_session(&user("")).is_none());
+
+        let texts: Vec<String> = transport.get_bodies().iter().map(|x| x["message"]["text"].to_string()).collect();
+        assert_eq!(texts, vec!["\"Your email ?\"", "\"It's not an email\"", "\"Thanks\""]);
+    }
+
+    #[test]
+    fn capture_first() {
+        let (client, transport) = recording_client();
+
+        let mut block = Block::new("Signup")
+            .cartBox(CartBox::new()
+                .capture("email")
+                .validate(Validator::EMAIL)
+                .reprompt("It's not an email"))
+            .cartBox(CartBox::new()
+                .text("Thanks {{email}}"));
+        block.set_client(client);
+
+        assert_eq!(block.root(&user("Signup")), None);
+        assert!(transport.get_bodies().is_empty());
+        assert_eq!(block.get_session(&user("")).unwrap().get_var("email"), None);
+
+        block.root(&user("bear@forest.fr"));
+        assert_eq!(transport.get_bodies()[0]["message"]["text"], "Thanks bear@forest.fr");
+    }
+
+    #[test]
+    fn branch_on_answer() {
+        let (client, transport) = recording_client();
+
+        let mut block = Block::new("Age")
+            .cartBox(CartBox::new()
+                .text("How old are you ?"))
+            .cartBox(CartBox::new()
+                .capture("age")
+                .validate(Validator::NUMBER)
+                .branch("Adult", Arc::new(|_, s| s.get_var("age").and_then(|x| x.parse::<u32>().ok()).unwrap_or(0) >= 18))
+                .branch("Child", Arc::new(|_, _| true)));
+        block.set_client(client);
+
+        assert_eq!(block.root(&user("Age")), None);
+        assert_eq!(block.root(&user("42")), Some(String::from("Adult")));
+        assert!(block.get_session(&user("")).is_none());
+
+        let mut adult = Block::new("Adult")
+            .cartBox(CartBox::new()
+                .text("Welcome {{age}} years old"));
+        adult.set_client(block.client.clone());
+        adult.set_store(block.sessions.clone());
+        assert_eq!(adult.jump(&user("42")), None);
+        assert_eq!(transport.get_bodies()[1]["message"]["text"], "Welcome 42 years old");
+    }
+
+    #[test]
+    fn media_reused() {
+        let (client, transport) = recording_client();
+
+        let mut block = Block::new("Photo")
+            .cartBox(CartBox::new()
+                .image("https://example.com/bear.png"));
+        block.set_client(client);
+
+        transport.respond(200, r#"{"recipient_id":"1","message_id":"mid.1","attachment_id":"1857777774821032"}"#);
+        block.root(&user("Photo"));
+        block.root(&BotUser::new("2", Arc::new(MessagingMessage::new("Photo"))));
+
+        let bodies = transport.get_bodies();
+        assert_eq!(bodies[0]["message"]["attachment"], json!({
+            "type": "image",
+            "payload": {"url": "https://example.com/bear.png", "is_reusable": true},
+        }));
+        assert_eq!(bodies[1]["message"]["attachment"]["payload"], json!({"attachment_id": "1857777774821032"}));
+    }
+
+    #[test]
+    fn delays_keep_the_box() {
+        let (client, transport) = recording_client();
+
+        let mut block = Block::new("Hello")
+            .cartBox(CartBox::new()
+                .text("Let me think")
+                .typing(Duration::from_millis(200)))
+            .cartBox(CartBox::new()
+                .text("Done"));
+        block.set_client(client.clone());
+
+        // Only the typing indicator goes out, the box waits for its delay
+        let start = Instant::now();
+        block.root(&user("Hello"));
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(block.resume(&user("")), None);
+        assert_eq!(transport.get_bodies().len(), 1);
+        assert_eq!(transport.get_bodies()[0]["sender_action"], "typing_on");
+        assert_eq!(block.get_session(&user("")).unwrap().get_index(), 0);
+
+        thread::sleep(Duration::from_millis(200));
+        block.resume(&user(""));
+        let bodies = transport.get_bodies();
+        assert_eq!(bodies[1]["message"]["text"], "Let me think");
+        assert_eq!(bodies[2]["message"]["text"], "Done");
+        assert!(block.get_session(&user("")).is_none());
+
+        // A transient failure is tried again after the backoff, up to the
+        // attempts of the retry policy, then the user stays on the box
+        let error = r#"{"error":{"message":"down","type":"OAuthException","code":2,"fbtrace_id":"a"}}"#;
+        let mut block = Block::new("Bye")
+            .cartBox(CartBox::new()
+                .text("See you"))
+            .cartBox(CartBox::new()
+                .text("Soon"));
+        block.set_client(client.clone());
+        transport.clear();
+        (0..3).for_each(|_| transport.respond(500, error));
+        block.root(&user("Bye"));
+        block.resume(&user(""));
+        block.resume(&user(""));
+        assert_eq!(block.resume(&user("")), None);
+        assert_eq!(transport.get_bodies().len(), 3);
+        let session = block.get_session(&user("")).unwrap();
+        assert_eq!((session.get_index(), session.get_resume()), (0, None));
+
+        // The next message sends it again, a permanent failure isn't retried
+        transport.respond(400, r#"{"error":{"message":"blocked","type":"OAuthException","code":551,"fbtrace_id":"b"}}"#);
+        block.root(&user("Hi"));
+        assert_eq!(block.get_session(&user("")).unwrap().get_resume(), None);
+        block.root(&user("Hi"));
+        let texts: Vec<_> = transport.get_bodies().iter().filter_map(|x| x["message"]["text"].as_str()).map(String::from).collect();
+        assert_eq!(texts, vec!["See you"; 5].into_iter().chain(vec!["Soon"]).collect::<Vec<_>>());
+        assert!(block.get_session(&user("")).is_none());
+    }
+}
@@ -0,0 +1,214 @@
+use super::block::Block;
+use super::lint::payload_target;
+use std::fmt::Write;
+
+enum EdgeKind {
+    // Next box of the block
+    SEQUENCE,
+    // Postback or quick reply button
+    PAYLOAD,
+    GOTO,
+    TRIGGER,
+}
+
+struct Edge {
+    from: String,
+    to: String,
+    label: String,
+    kind: EdgeKind,
+}
+
+// Boxes grouped by block and the ways between them
+struct Graph<'a> {
+    blocks: Vec<&'a Block>,
+    default: usize,
+    labels: Vec<Vec<String>>,
+    triggers: Vec<(String, String)>,
+    edges: Vec<Edge>,
+}
+
+// Longest label kept on a node
+const MAX_LABEL: usize = 40;
+
+impl<'a> Graph<'a> {
+    fn new(blocks: &'a [Block], default: &'a Block) -> Self {
+        let all: Vec<&Block> = blocks.iter().chain(std::iter::once(default)).collect();
+        let first = |name: &str| all.iter().position(|x| x.get_name() == name).map(|x| node(x, 0));
+
+        let mut labels = Vec::new();
+        let mut triggers = Vec::new();
+        let mut edges = Vec::new();
+
+        for (b, block) in all.iter().enumerate() {
+            let mut texts = Vec::new();
+
+            for (i, pipe) in block.get_pipe().iter().enumerate() {
+                let outline = pipe.outline();
+                texts.push(shorten(outline.get_label()));
+
+                if i + 1 < block.get_pipe().len() {
+                    edges.push(Edge{from: node(b, i), to: node(b, i + 1), label: String::new(), kind: EdgeKind::SEQUENCE});
+                }
+                for payload in outline.get_payloads() {
+                    if let Some(to) = payload_target(blocks, payload).and_then(|x| first(x.get_name())) {
+                        edges.push(Edge{from: node(b, i), to: to, label: shorten(payload), kind: EdgeKind::PAYLOAD});
+                    }
+                }
+                for goto in outline.get_gotos() {
+                    if let Some(to) = first(goto) {
+                        edges.push(Edge{from: node(b, i), to: to, label: String::from("goto"), kind: EdgeKind::GOTO});
+                    }
+                }
+            }
+
+            if texts.is_empty() {
+                texts.push(String::from("(no box)"));
+            }
+            labels.push(texts);
+
+            if !block.get_triggers().is_empty() {
+                let text = block.get_triggers().iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" | ");
+                let id = format!("t{}", b);
+                edges.push(Edge{from: id.clone(), to: node(b, 0), label: String::new(), kind: EdgeKind::TRIGGER});
+                triggers.push((id, shorten(&text)));
+            }
+        }
+
+        Graph{
+            default: all.len() - 1,
+            blocks: all,
+            labels: labels,
+            triggers: triggers,
+            edges: edges,
+        }
+    }
+
+    fn block_label(&self, b: usize) -> String {
+        match b == self.default {
+            true => format!("{} (default)", self.blocks[b].get_name()),
+            false => String::from(self.blocks[b].get_name()),
+        }
+    }
+}
+
+fn node(block: usize, index: usize) -> String {
+    format!("n{}_{}", block, index)
+}
+
+fn shorten(text: &str) -> String {
+    let text = text.replace('\n', " ");
+    match text.chars().count() > MAX_LABEL {
+        true => format!("{}...", text.chars().take(MAX_LABEL).collect::<String>()),
+        false => text,
+    }
+}
+
+// Graphviz, render with: dot -Tsvg flows.dot > flows.svg
+pub fn to_dot(blocks: &[Block], default: &Block) -> String {
+    let graph = Graph::new(blocks, default);
+    let escape = |x: &str| x.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut out = String::from("digraph flows {\n    rankdir=LR;\n    node [shape=box, style=rounded];\n");
+
+    for (b, texts) in graph.labels.iter().enumerate() {
+        writeln!(out, "    subgraph cluster_{} {{", b).unwrap();
+        writeln!(out, "        label=\"{}\";", escape(&graph.block_label(b))).unwrap();
+        if b == graph.default {
+            writeln!(out, "        style=dashed;").unwrap();
+        }
+        for (i, text) in texts.iter().enumerate() {
+            writeln!(out, "        {} [label=\"{}\"];", node(b, i), escape(text)).unwrap();
+        }
+        writeln!(out, "    }}").unwrap();
+    }
+
+    for (id, text) in &graph.triggers {
+        writeln!(out, "    {} [shape=ellipse, style=filled, label=\"{}\"];", id, escape(text)).unwrap();
+    }
+
+    for edge in &graph.edges {
+        let style = match edge.kind {
+            EdgeKind::SEQUENCE | EdgeKind::TRIGGER => String::new(),
+            EdgeKind::PAYLOAD => format!(" [style=dashed, label=\"{}\"]", escape(&edge.label)),
+            EdgeKind::GOTO => format!(" [style=bold, label=\"{}\"]", escape(&edge.label)),
+        };
+        writeln!(out, "    {} -> {}{};", edge.from, edge.to, style).unwrap();
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+// Mermaid flowchart, rendered by most markdown docs
+pub fn to_mermaid(blocks: &[Block], default: &Block) -> String {
+    let graph = Graph::new(blocks, default);
+    let escape = |x: &str| x.replace('"', "#quot;");
+    let mut out = String::from("flowchart LR\n");
+
+    for (b, texts) in graph.labels.iter().enumerate() {
+        writeln!(out, "    subgraph b{}[\"{}\"]", b, escape(&graph.block_label(b))).unwrap();
+        for (i, text) in texts.iter().enumerate() {
+            writeln!(out, "        {}[\"{}\"]", node(b, i), escape(text)).unwrap();
+        }
+        writeln!(out, "    end").unwrap();
+    }
+
+    for (id, text) in &graph.triggers {
+        writeln!(out, "    {}([\"{}\"])", id, escape(text)).unwrap();
+    }
+
+    for edge in &graph.edges {
+        let arrow = match edge.kind {
+            EdgeKind::SEQUENCE | EdgeKind::TRIGGER => String::from("-->"),
+            EdgeKind::PAYLOAD => format!("-.->|\"{}\"|", escape(&edge.label)),
+            EdgeKind::GOTO => format!("==>|\"{}\"|", escape(&edge.label)),
+        };
+        writeln!(out, "    {} {} {}", edge.from, arrow, edge.to).unwrap();
+    }
+
+    writeln!(out, "    style b{} stroke-dasharray: 5 5", graph.default).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{to_dot, to_mermaid};
+    use crate::utils::block::{Block, CartBox};
+    use crate::utils::trigger::Trigger;
+    use std::sync::Arc;
+
+    #[test]
+    fn export_flows() {
+        let blocks = vec![
+            Block::new("Hello")
+                .trigger(Trigger::NOCASE(String::from("hello")))
+                .cartBox(CartBox::new()
+                    .text("Say \"hi\"")
+                    .button_postback("Go", "Shop"))
+                .cartBox(CartBox::new()
+                    .branch("Shop", Arc::new(|_, _| true))),
+            Block::new("Shop")
+                .cartBox(CartBox::new()
+                    .text("Welcome")),
+        ];
+        let default = Block::new("default")
+            .cartBox(CartBox::new()
+                .text("Sorry"));
+
+        let dot = to_dot(&blocks, &default);
+        assert!(dot.contains("        n0_0 [label=\"Say \\\"hi\\\"\"];\n"));
+        assert!(dot.contains("    n0_0 -> n0_1;\n"));
+        assert!(dot.contains("    n0_0 -> n1_0 [style=dashed, label=\"Shop\"];\n"));
+        assert!(dot.contains("    n0_1 -> n1_0 [style=bold, label=\"goto\"];\n"));
+        assert!(dot.contains("    t0 [shape=ellipse, style=filled, label=\"nocase hello\"];\n"));
+        assert!(dot.contains("    t0 -> n0_0;\n"));
+        assert!(dot.contains("label=\"default (default)\";\n        style=dashed;"));
+
+        let mermaid = to_mermaid(&blocks, &default);
+        assert!(mermaid.starts_with("flowchart LR\n    subgraph b0[\"Hello\"]\n        n0_0[\"Say #quot;hi#quot;\"]\n"));
+        assert!(mermaid.contains("    n0_0 -.->|\"Shop\"| n1_0\n"));
+        assert!(mermaid.contains("    n0_1 ==>|\"goto\"| n1_0\n"));
+        assert!(mermaid.contains("    t0([\"nocase hello\"])\n"));
+        assert!(mermaid.ends_with("    style b2 stroke-dasharray: 5 5\n"));
+    }
+}
@@ -0,0 +1,607 @@
+use super::block::{Block, CartBox};
+use super::trigger::Trigger;
+use super::validator::Validator;
+use super::{BotUser, MessagingType, AttachmentType};
+use super::session::Session;
+use crate::api::MessageTag;
+use crate::api::button::Button;
+use crate::api::card::{CardGeneric, CardButtons, DefaultAction};
+use serde::de::{self, Deserializer, Visitor};
+use serde_derive::Deserialize;
+use regex::Regex;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Error of a flow file, with the place of the mistake when known
+#[derive(Debug)]
+pub struct FlowError {
+    line: Option<usize>,
+    column: Option<usize>,
+    message: String,
+}
+
+impl FlowError {
+    // Error of the whole flow, like a failed validation
+    pub fn new(message: &str) -> Self {
+        FlowError{
+            line: None,
+            column: None,
+            message: String::from(message),
+        }
+    }
+
+    pub fn get_line(&self) -> Option<usize> {
+        self.line
+    }
+
+    pub fn get_column(&self) -> Option<usize> {
+        self.column
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for FlowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(l), Some(c)) => write!(f,"line {} column {}: {}",l,c,self.message),
+            (Some(l), None) => write!(f,"line {}: {}",l,self.message),
+            _ => write!(f,"{}",self.message),
+        }
+    }
+}
+
+impl std::error::Error for FlowError {}
+
+impl From<serde_yaml::Error> for FlowError {
+    fn from(e: serde_yaml::Error) -> Self {
+        FlowError{
+            line: e.location().map(|x| x.line()),
+            column: e.location().map(|x| x.column()),
+            message: without_place(&e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for FlowError {
+    fn from(e: serde_json::Error) -> Self {
+        FlowError{
+            line: Some(e.line()),
+            column: Some(e.column()),
+            message: without_place(&e.to_string()),
+        }
+    }
+}
+
+// The messages of serde_yaml and serde_json end with the place, keep it apart
+fn without_place(message: &str) -> String {
+    match message.find(" at line ") {
+        Some(i) => String::from(&message[..i]),
+        None => String::from(message),
+    }
+}
+
+impl From<std::io::Error> for FlowError {
+    fn from(e: std::io::Error) -> Self {
+        FlowError::new(&e.to_string())
+    }
+}
+
+// Blocks of a bot written in YAML or JSON
+//
+// default:
+//   name: default
+//   boxes:
+//     - text: "Sorry I don't understand"
+// blocks:
+//   - name: Hello
+//     triggers:
+//       - nocase: hi
+//       - regex: "weather in (?P<city>\w+)"
+//     utterances: [good morning]
+//     boxes:
+//       - text: "Hello {{first_name}}"
+//         quick_replies:
+//           - {title: Push, payload: Hello}
+//       - capture: age
+//         validate: number
+//         branches:
+//           - {goto: Adult, var: age, matches: "^[0-9]{2,}$"}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Flow {
+    default: Option<BlockDef>,
+    #[serde(default)]
+    blocks: Vec<BlockDef>,
+}
+
+impl Flow {
+    // Read a .json file as JSON and anything else as YAML
+    pub fn load(path: &str) -> Result<Flow, FlowError> {
+        let text = fs::read_to_string(path)?;
+
+        if path.ends_with(".json") {
+            Flow::from_json(&text)
+        }
+        else {
+            Flow::from_yaml(&text)
+        }
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Flow, FlowError> {
+        Ok(serde_yaml::from_str(text)?)
+    }
+
+    pub fn from_json(text: &str) -> Result<Flow, FlowError> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    // Blocks built like the builder chains would, and the default block
+    pub fn build(self) -> (Vec<Block>, Option<Block>) {
+        let blocks = self.blocks.into_iter().map(BlockDef::build).collect();
+        (blocks, self.default.map(BlockDef::build))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockDef {
+    name: String,
+    #[serde(default)]
+    triggers: Vec<TriggerDef>,
+    #[serde(default)]
+    utterances: Vec<String>,
+    // Seconds a user may stay silent in the block
+    ttl: Option<u64>,
+    #[serde(default)]
+    boxes: Vec<BoxDef>,
+}
+
+impl BlockDef {
+    fn build(self) -> Block {
+        let mut block = Block::new(&self.name);
+        for trigger in self.triggers {
+            block = block.trigger(trigger.build());
+        }
+        for utterance in &self.utterances {
+            block = block.utterance(utterance);
+        }
+        if let Some(e) = self.ttl {
+            block = block.ttl(Duration::from_secs(e));
+        }
+        for cartbox in self.boxes {
+            block = block.cartBox(cartbox.build());
+        }
+        block
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+enum TriggerDef {
+    Exact(String),
+    Nocase(String),
+    Normalized(String),
+    Regex(#[serde(deserialize_with = "regex")] Regex),
+    Keywords(Vec<String>),
+    Nlp{name: String, threshold: f64},
+    Attachment(AttachmentDef),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AttachmentDef {
+    Image,
+    Audio,
+    Video,
+    File,
+    Location,
+    Sticker,
+}
+
+impl TriggerDef {
+    fn build(self) -> Trigger {
+        match self {
+            TriggerDef::Exact(e) => Trigger::EXACT(e),
+            TriggerDef::Nocase(e) => Trigger::NOCASE(e),
+            TriggerDef::Normalized(e) => Trigger::NORMALIZED(e),
+            TriggerDef::Regex(e) => Trigger::REGEX(e),
+            TriggerDef::Keywords(e) => Trigger::keywords(&e.iter().map(|x| x.as_str()).collect::<Vec<&str>>()),
+            TriggerDef::Nlp{name, threshold} => Trigger::NLP(name, threshold),
+            TriggerDef::Attachment(e) => Trigger::ATTACHMENT(match e {
+                AttachmentDef::Image => AttachmentType::IMAGE,
+                AttachmentDef::Audio => AttachmentType::AUDIO,
+                AttachmentDef::Video => AttachmentType::VIDEO,
+                AttachmentDef::File => AttachmentType::FILE,
+                AttachmentDef::Location => AttachmentType::LOCATION,
+                AttachmentDef::Sticker => AttachmentType::STICKER,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+enum ValidatorDef {
+    Regex(#[serde(deserialize_with = "regex")] Regex),
+    Number,
+    Email,
+    Phone,
+    Date,
+}
+
+impl ValidatorDef {
+    fn build(self) -> Validator {
+        match self {
+            ValidatorDef::Regex(e) => Validator::REGEX(e),
+            ValidatorDef::Number => Validator::NUMBER,
+            ValidatorDef::Email => Validator::EMAIL,
+            ValidatorDef::Phone => Validator::PHONE,
+            ValidatorDef::Date => Validator::DATE,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum TagDef {
+    ConfirmedEventUpdate,
+    PostPurchaseUpdate,
+    AccountUpdate,
+    HumanAgent,
+}
+
+impl TagDef {
+    fn build(self) -> MessageTag {
+        match self {
+            TagDef::ConfirmedEventUpdate => MessageTag::CONFIRMEDEVENTUPDATE,
+            TagDef::PostPurchaseUpdate => MessageTag::POSTPURCHASEUPDATE,
+            TagDef::AccountUpdate => MessageTag::ACCOUNTUPDATE,
+            TagDef::HumanAgent => MessageTag::HUMANAGENT,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BoxDef {
+    text: Option<String>,
+    #[serde(default)]
+    quick_replies: Vec<QuickReplyDef>,
+    #[serde(default)]
+    cards: Vec<CardDef>,
+    image: Option<String>,
+    video: Option<String>,
+    audio: Option<String>,
+    file: Option<String>,
+    asset: Option<String>,
+    // Milliseconds of typing_on before the content
+    typing: Option<u64>,
+    // Message tag, to send the box out of the 24h window
+    tag: Option<TagDef>,
+    capture: Option<String>,
+    validate: Option<ValidatorDef>,
+    reprompt: Option<String>,
+    #[serde(default)]
+    branches: Vec<BranchDef>,
+    #[serde(default)]
+    follow_ups: Vec<FollowUpDef>,
+    // Block to jump to once the box is done
+    goto: Option<String>,
+}
+
+impl BoxDef {
+    fn build(self) -> CartBox {
+        let mut cartbox = CartBox::new();
+
+        if let Some(e) = &self.capture {
+            cartbox = cartbox.capture(e);
+        }
+        if let Some(e) = self.validate {
+            cartbox = cartbox.validate(e.build());
+        }
+        if let Some(e) = &self.reprompt {
+            cartbox = cartbox.reprompt(e);
+        }
+        if let Some(e) = &self.text {
+            cartbox = cartbox.text(e);
+        }
+        for e in &self.quick_replies {
+            cartbox = cartbox.button_postback(&e.title, &e.payload);
+        }
+        for e in self.cards {
+            cartbox = match e {
+                CardDef::Generic(card) => cartbox.card(card.build()),
+                CardDef::Buttons(card) => cartbox.card(card.build()),
+            };
+        }
+        if let Some(e) = &self.image {
+            cartbox = cartbox.image(e);
+        }
+        if let Some(e) = &self.video {
+            cartbox = cartbox.video(e);
+        }
+        if let Some(e) = &self.audio {
+            cartbox = cartbox.audio(e);
+        }
+        if let Some(e) = &self.file {
+            cartbox = cartbox.file(e);
+        }
+        if let Some(e) = &self.asset {
+            cartbox = cartbox.asset(e);
+        }
+        if let Some(e) = self.typing {
+            cartbox = cartbox.typing(Duration::from_millis(e));
+        }
+        if let Some(e) = self.tag {
+            cartbox = cartbox.tag(e.build());
+        }
+        for e in &self.follow_ups {
+            cartbox = match e.cancel_on_reply {
+                true => cartbox.follow_up(Duration::from_secs(e.after), &e.text),
+                false => cartbox.reminder(Duration::from_secs(e.after), &e.text),
+            };
+        }
+        for e in self.branches {
+            let goto = e.goto.clone();
+            cartbox = cartbox.branch(&goto, Arc::new(move |user, session| e.holds(user, session)));
+        }
+        if let Some(e) = &self.goto {
+            cartbox = cartbox.branch(e, Arc::new(|_, _| true));
+        }
+        cartbox
+    }
+}
+
+// Text sent after some seconds, dropped when the user answers first unless
+// cancel_on_reply is false
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FollowUpDef {
+    after: u64,
+    text: String,
+    #[serde(default = "yes")]
+    cancel_on_reply: bool,
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuickReplyDef {
+    title: String,
+    payload: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+enum CardDef {
+    Generic(GenericDef),
+    Buttons(ButtonsDef),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GenericDef {
+    title: String,
+    subtitle: Option<String>,
+    image: Option<String>,
+    // Url opened when the card is tapped
+    url: Option<String>,
+    #[serde(default)]
+    buttons: Vec<ButtonDef>,
+}
+
+impl GenericDef {
+    fn build(self) -> CardGeneric {
+        let mut card = CardGeneric::new(&self.title);
+        if let Some(e) = &self.subtitle {
+            card = card.subtitle(e);
+        }
+        if let Some(e) = &self.image {
+            card = card.image(e);
+        }
+        if let Some(e) = &self.url {
+            card = card.default_action(DefaultAction::new(&self.title, e));
+        }
+        for e in self.buttons {
+            card = card.button(e.0);
+        }
+        card
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ButtonsDef {
+    text: String,
+    buttons: Vec<ButtonDef>,
+}
+
+impl ButtonsDef {
+    fn build(self) -> CardButtons {
+        let mut card = CardButtons::new(&self.text);
+        for e in self.buttons {
+            card = card.button(e.0);
+        }
+        card
+    }
+}
+
+// {title, payload} for a postback or {title, url} for a link
+#[derive(Deserialize)]
+#[serde(try_from = "ButtonFields")]
+struct ButtonDef(Button);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ButtonFields {
+    title: String,
+    payload: Option<String>,
+    url: Option<String>,
+}
+
+impl TryFrom<ButtonFields> for ButtonDef {
+    type Error = String;
+
+    fn try_from(e: ButtonFields) -> Result<Self, Self::Error> {
+        match (e.payload, e.url) {
+            (Some(payload), None) => Ok(ButtonDef(Button::new_button_pb(&e.title, &payload))),
+            (None, Some(url)) => Ok(ButtonDef(Button::URL(e.title, url))),
+            _ => Err(format!("button {} needs either a payload or an url", e.title)),
+        }
+    }
+}
+
+// Jump when every condition given holds
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BranchDef {
+    goto: String,
+    var: Option<String>,
+    equals: Option<String>,
+    #[serde(default, deserialize_with = "regex_option")]
+    matches: Option<Regex>,
+    nlp: Option<String>,
+    threshold: Option<f64>,
+}
+
+impl BranchDef {
+    fn holds(&self, user: &BotUser, session: &Session) -> bool {
+        if let Some(name) = &self.var {
+            let value = match session.get_var(name) {
+                Some(e) => e,
+                None => return false,
+            };
+            if self.equals.as_ref().map(|x| x != value).unwrap_or(false) {
+                return false
+            }
+            if self.matches.as_ref().map(|x| !x.is_match(value)).unwrap_or(false) {
+                return false
+            }
+        }
+        else if let Some(regex) = &self.matches {
+            let message = user.get_message();
+            if !matches!(message.message_type(), MessagingType::MESSAGE(_)) || !regex.is_match(message.message()) {
+                return false
+            }
+        }
+
+        match &self.nlp {
+            Some(e) => user.nlp_confidence(e) > self.threshold.unwrap_or(0.5),
+            None => true,
+        }
+    }
+}
+
+// Compile the regex while parsing so a bad one points at its line
+fn regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_str(RegexVisitor)
+}
+
+struct RegexVisitor;
+
+impl<'de> Visitor<'de> for RegexVisitor {
+    type Value = Regex;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"a regex")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Regex, E> {
+        Regex::new(text).map_err(E::custom)
+    }
+}
+
+fn regex_option<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    regex(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Flow;
+
+    const FLOW: &str = r#"
+default:
+  name: default
+  boxes:
+    - text: "Sorry I don't understand"
+blocks:
+  - name: Hello
+    ttl: 3600
+    triggers:
+      - nocase: hi
+      - regex: "weather in (?P<city>\\w+)"
+      - nlp: {name: "wit$greetings", threshold: 0.8}
+      - attachment: image
+    utterances: [good morning]
+    boxes:
+      - text: "Hello {{first_name}}"
+        typing: 500
+        quick_replies:
+          - {title: Push, payload: Hello}
+      - cards:
+          - generic:
+              title: Bear
+              subtitle: Bouyah
+              buttons:
+                - {title: Site, url: "https://example.com"}
+          - buttons:
+              text: Can you choose !
+              buttons:
+                - {title: not me !, payload: Hello}
+      - capture: age
+        validate: number
+        reprompt: A number please
+        follow_ups:
+          - {after: 7200, text: "Still there ?"}
+        branches:
+          - {goto: Adult, var: age, matches: "^[0-9]{2,}$"}
+        goto: Child
+"#;
+
+    #[test]
+    fn load_yaml() {
+        let (blocks, default) = Flow::from_yaml(FLOW).unwrap().build();
+        assert_eq!(default.unwrap().get_name(), "default");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].get_name(), "Hello");
+        assert_eq!(blocks[0].get_triggers().len(), 4);
+        assert_eq!(blocks[0].get_ttl(), Some(std::time::Duration::from_secs(3600)));
+        assert_eq!(blocks[0].get_utterances(), &[String::from("good morning")]);
+        assert_eq!(blocks[0].get_pipe().len(), 3);
+
+        let json = r#"{"blocks": [{"name": "Hello", "boxes": [{"text": "Hi"}]}]}"#;
+        assert_eq!(Flow::from_json(json).unwrap().build().0[0].get_pipe().len(), 1);
+    }
+
+    #[test]
+    fn errors_point_at_line() {
+        let err = Flow::from_yaml(&FLOW.replace("(?P<city>", "(?P<city")).err().unwrap();
+        assert_eq!(err.get_line(), Some(11));
+
+        let err = Flow::from_yaml(&FLOW.replace("reprompt:", "reprompted:")).err().unwrap();
+        assert_eq!(err.get_line(), Some(32));
+        assert!(err.get_message().contains("reprompted"));
+
+        let err = Flow::from_yaml(&FLOW.replace("url: \"https://example.com\"", "link: x")).err().unwrap();
+        assert_eq!(err.get_line(), Some(25));
+
+        let err = Flow::from_json("{\"blocks\": [\n{\"name\": 1}]}").err().unwrap();
+        assert_eq!(err.get_line(), Some(2));
+    }
+}
@@ -0,0 +1,149 @@
+use super::trigger::normalize;
+use std::collections::HashMap;
+
+// What the user wants, like "weather" with the entity city=Tours
+#[derive(Clone)]
+pub struct Intent {
+    name: String,
+    confidence: f64,
+    entities: HashMap<String,String>,
+}
+
+impl Intent {
+    pub fn new(name: &str, confidence: f64) -> Self {
+        Intent{
+            name: String::from(name),
+            confidence: confidence,
+            entities: HashMap::new(),
+        }
+    }
+
+    pub fn entity(mut self, name: &str, value: &str) -> Self {
+        self.entities.insert(String::from(name), String::from(value));
+        self
+    }
+
+    // Name of the block to start
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // Between 0 and 1
+    pub fn get_confidence(&self) -> f64 {
+        self.confidence
+    }
+
+    pub fn get_entities(&self) -> &HashMap<String,String> {
+        &self.entities
+    }
+}
+
+// Classify a text message, the entities become session variables
+pub trait IntentRecognizer: Send + Sync {
+    fn recognize(&self, text: &str) -> Option<Intent>;
+}
+
+// Offline recognizer comparing the message to the utterances of the blocks
+// with a tf-idf cosine similarity
+#[derive(Clone, Default)]
+pub struct TfIdfRecognizer {
+    idf: HashMap<String,f64>,
+    unknown_idf: f64,
+    utterances: Vec<(String, HashMap<String,f64>)>,
+}
+
+impl TfIdfRecognizer {
+    // Examples of each intent, like ("weather", ["will it rain", "is it sunny"])
+    pub fn new(examples: &[(String, Vec<String>)]) -> Self {
+        let docs: Vec<(String, Vec<String>)> = examples.iter()
+            .flat_map(|(name, texts)| texts.iter().map(move |x| (name.clone(), tokens(x))))
+            .filter(|x| !x.1.is_empty())
+            .collect();
+
+        let mut df: HashMap<String,f64> = HashMap::new();
+        for (_, words) in &docs {
+            let mut seen: Vec<&String> = words.iter().collect();
+            seen.sort();
+            seen.dedup();
+            seen.into_iter().for_each(|x| *df.entry(x.clone()).or_insert(0.0) += 1.0);
+        }
+
+        let n = docs.len() as f64;
+        let idf: HashMap<String,f64> = df.into_iter()
+            .map(|(word, count)| (word, ((1.0 + n) / (1.0 + count)).ln() + 1.0))
+            .collect();
+
+        let mut recognizer = TfIdfRecognizer{
+            idf: idf,
+            unknown_idf: (1.0 + n).ln() + 1.0,
+            utterances: Vec::new(),
+        };
+        recognizer.utterances = docs.iter().map(|(name, words)| (name.clone(), recognizer.vector(words))).collect();
+        recognizer
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utterances.is_empty()
+    }
+
+    // Unit tf-idf vector, the words never seen lower the similarity
+    fn vector(&self, words: &[String]) -> HashMap<String,f64> {
+        let mut vector: HashMap<String,f64> = HashMap::new();
+        for word in words {
+            let idf = self.idf.get(word).cloned().unwrap_or(self.unknown_idf);
+            *vector.entry(word.clone()).or_insert(0.0) += idf;
+        }
+
+        let norm = vector.values().map(|x| x * x).sum::<f64>().sqrt();
+        vector.values_mut().for_each(|x| *x /= norm);
+        vector
+    }
+}
+
+impl IntentRecognizer for TfIdfRecognizer {
+    fn recognize(&self, text: &str) -> Option<Intent> {
+        let words = tokens(text);
+        if words.is_empty() {
+            return None
+        }
+
+        let query = self.vector(&words);
+        self.utterances.iter()
+            .map(|(name, vector)| {
+                let score: f64 = query.iter().filter_map(|(k,v)| vector.get(k).map(|x| x * v)).sum();
+                (name, score)
+            })
+            .filter(|x| x.1 > 0.0)
+            .fold(None, |best: Option<(&String, f64)>, x| match best {
+                Some(b) if b.1 >= x.1 => Some(b),
+                _ => Some(x),
+            })
+            .map(|(name, score)| Intent::new(name, score))
+    }
+}
+
+fn tokens(text: &str) -> Vec<String> {
+    normalize(text).split(' ').filter(|x| !x.is_empty()).map(String::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{IntentRecognizer, TfIdfRecognizer};
+
+    #[test]
+    fn recognize_utterances() {
+        let recognizer = TfIdfRecognizer::new(&[
+            (String::from("Hello"), vec![String::from("hello"), String::from("hi"), String::from("good morning")]),
+            (String::from("Weather"), vec![String::from("will it rain today"), String::from("what's the weather like")]),
+        ]);
+
+        let intent = recognizer.recognize("Hi there !").unwrap();
+        assert_eq!(intent.get_name(), "Hello");
+        assert!(intent.get_confidence() > 0.5);
+
+        assert_eq!(recognizer.recognize("Is it going to rain ?").unwrap().get_name(), "Weather");
+        assert!(recognizer.recognize("blue elephant").is_none());
+        assert!(recognizer.recognize("the weather of my blue elephant").unwrap().get_confidence() < 0.5);
+    }
+}
@@ -0,0 +1,254 @@
+use super::block::Block;
+use super::{BotUser, MessagingMessage};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Clone,PartialEq,Debug)]
+pub enum Level {
+    // launch refuses to start
+    ERROR,
+    WARNING,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::ERROR => write!(f,"error"),
+            Level::WARNING => write!(f,"warning"),
+        }
+    }
+}
+
+// Mistake found in the flows, located like "Hello" or "Hello[2]" for a box
+#[derive(Clone,Debug)]
+pub struct Issue {
+    level: Level,
+    location: String,
+    message: String,
+}
+
+impl Issue {
+    pub fn new(level: Level, location: &str, message: &str) -> Self {
+        Issue{
+            level: level,
+            location: String::from(location),
+            message: String::from(message),
+        }
+    }
+
+    pub fn get_level(&self) -> &Level {
+        &self.level
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.level == Level::ERROR
+    }
+
+    pub fn get_location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"{}: {}: {}",self.level,self.location,self.message)
+    }
+}
+
+// What a PipeBox sends and where it can lead, for the linter and the exports
+#[derive(Clone,Default)]
+pub struct Outline {
+    label: String,
+    payloads: Vec<String>,
+    gotos: Vec<String>,
+    issues: Vec<(Level,String)>,
+}
+
+impl Outline {
+    pub fn new(label: &str) -> Self {
+        Outline{
+            label: String::from(label),
+            ..Outline::default()
+        }
+    }
+
+    // Postback or quick reply payload, routed like a message of the user
+    pub fn payload(mut self, payload: &str) -> Self {
+        self.payloads.push(String::from(payload));
+        self
+    }
+
+    // Block the box can jump to
+    pub fn goto(mut self, block: &str) -> Self {
+        self.gotos.push(String::from(block));
+        self
+    }
+
+    pub fn issue(mut self, level: Level, message: &str) -> Self {
+        self.issues.push((level, String::from(message)));
+        self
+    }
+
+    pub fn get_label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn get_payloads(&self) -> &[String] {
+        &self.payloads
+    }
+
+    pub fn get_gotos(&self) -> &[String] {
+        &self.gotos
+    }
+
+    pub fn get_issues(&self) -> &[(Level,String)] {
+        &self.issues
+    }
+}
+
+// Block started by a payload, through its name or its triggers, unknown
+// for a payload with {{variables}} since it's only rendered for a user
+pub fn payload_target<'a>(blocks: &'a [Block], payload: &str) -> Option<&'a Block> {
+    if is_templated(payload) {
+        return None
+    }
+
+    let user = BotUser::new("", Arc::new(MessagingMessage::new(payload)));
+    blocks.iter()
+        .filter_map(|x| x.matching(&user).map(|m| (m.0, x)))
+        .min_by_key(|x| x.0)
+        .map(|x| x.1)
+}
+
+fn is_templated(payload: &str) -> bool {
+    payload.contains("{{")
+}
+
+// Walk every block, box, card and button of the flows, the welcome back
+// block being an entry point like the triggers
+pub fn lint(blocks: &[Block], default: &Block, welcome_back: Option<&str>) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let all: Vec<&Block> = blocks.iter().chain(std::iter::once(default)).collect();
+    let exists = |name: &str| all.iter().any(|x| x.get_name() == name);
+
+    let mut names = HashSet::new();
+    for block in blocks {
+        if !names.insert(block.get_name()) {
+            issues.push(Issue::new(Level::ERROR, block.get_name(), "another block has the same name, only the first one is used"));
+        }
+    }
+
+    for block in &all {
+        if block.get_pipe().is_empty() {
+            issues.push(Issue::new(Level::WARNING, block.get_name(), "block has no box, the user gets no answer"));
+        }
+
+        for (i, pipe) in block.get_pipe().iter().enumerate() {
+            let outline = pipe.outline();
+            let location = format!("{}[{}]", block.get_name(), i);
+
+            for (level, message) in outline.get_issues() {
+                issues.push(Issue::new(level.clone(), &location, message));
+            }
+            for payload in outline.get_payloads() {
+                if !is_templated(payload) && payload_target(blocks, payload).is_none() {
+                    issues.push(Issue::new(Level::ERROR, &location, &format!("payload {} names no block", payload)));
+                }
+            }
+            for goto in outline.get_gotos() {
+                if !exists(goto) {
+                    issues.push(Issue::new(Level::ERROR, &location, &format!("goto {} names no block", goto)));
+                }
+            }
+        }
+    }
+
+    // Users come in by the default and welcome back blocks and by the
+    // triggers and utterances, then follow the payloads and gotos
+    let mut reached: HashSet<&str> = HashSet::new();
+    let mut queue: VecDeque<&Block> = all.iter()
+        .filter(|x| x.get_name() == default.get_name() || Some(x.get_name()) == welcome_back
+            || !x.get_triggers().is_empty() || !x.get_utterances().is_empty())
+        .cloned()
+        .collect();
+
+    while let Some(block) = queue.pop_front() {
+        if !reached.insert(block.get_name()) {
+            continue
+        }
+
+        for pipe in block.get_pipe() {
+            let outline = pipe.outline();
+            let targets = outline.get_payloads().iter().filter_map(|x| payload_target(blocks, x))
+                .chain(outline.get_gotos().iter().filter_map(|x| all.iter().find(|b| b.get_name() == x).cloned()));
+            queue.extend(targets);
+        }
+    }
+
+    for block in blocks {
+        if !reached.contains(block.get_name()) {
+            issues.push(Issue::new(Level::WARNING, block.get_name(), "unreachable, no trigger, utterance, payload, goto or welcome back leads here"));
+        }
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::lint;
+    use crate::utils::block::{Block, CartBox};
+    use crate::utils::trigger::Trigger;
+    use crate::api::card::CardButtons;
+    use regex::Regex;
+    use std::sync::Arc;
+
+    #[test]
+    fn lint_flows() {
+        let blocks = vec![
+            Block::new("Hello")
+                .trigger(Trigger::NOCASE(String::from("hello")))
+                .cartBox(CartBox::new()
+                    .text("Hi")
+                    .button_postback("Go", "Shop"))
+                .cartBox(CartBox::new()
+                    .card(CardButtons::new("Empty")))
+                .cartBox(CartBox::new()
+                    .branch("Nowhere", Arc::new(|_, _| true))),
+            Block::new("Shop")
+                .cartBox(CartBox::new()
+                    .button_postback("Back", "Helo"))
+                .cartBox(CartBox::new()
+                    .text("Track it")
+                    .button_postback("Track", "TRACK_{{order_id}}")),
+            Block::new("Track")
+                .trigger(Trigger::REGEX(Regex::new(r"TRACK_\d+").unwrap()))
+                .cartBox(CartBox::new()
+                    .text("On its way")),
+            Block::new("Back")
+                .cartBox(CartBox::new()
+                    .text("Welcome back")),
+            Block::new("Lost")
+                .cartBox(CartBox::new()
+                    .text("Nobody comes here")),
+        ];
+        let default = Block::new("default")
+            .cartBox(CartBox::new()
+                .text("Sorry"));
+
+        let issues: Vec<String> = lint(&blocks, &default, Some("Back")).iter().map(|x| x.to_string()).collect();
+        assert_eq!(issues, vec![
+            "error: Hello[1]: card has no button",
+            "error: Hello[2]: goto Nowhere names no block",
+            "error: Shop[0]: box sends nothing, it would send \"Basic Text\"",
+            "error: Shop[0]: payload Helo names no block",
+            "warning: Lost: unreachable, no trigger, utterance, payload, goto or welcome back leads here",
+        ].iter().map(|x| x.to_string()).collect::<Vec<String>>());
+    }
+}
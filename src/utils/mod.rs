@@ -1,13 +1,58 @@
 pub mod block;
+pub mod signature;
+pub mod session;
+pub mod validator;
+pub mod template;
+pub mod trigger;
+pub mod intent;
+pub mod nlp;
+pub mod flow;
+pub mod lint;
+pub mod export;
 
 use std::fmt;
+use std::time::Duration;
 use serde::de::{self, Deserialize, Deserializer};
 use serde_json::Value;
 use std::sync::Arc;
+use log::{debug, warn};
+use crate::api::client::Client;
+use crate::api::retry::RetryPolicy;
+use crate::api::WindowPolicy;
+use session::Session;
+use nlp::Nlp;
+use lint::Outline;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Write then rename so a crash never leaves half a file
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)
+}
 
 pub enum MessagingType<'a> {
     POSTBACK(&'a MessagingPostback),
     MESSAGE(&'a MessagingMessage),
+    ATTACHMENT(&'a MessagingAttachment),
+    DELIVERY(&'a MessagingDelivery),
+    READ(&'a MessagingRead),
+    ECHO(&'a MessagingEcho),
+    REACTION(&'a MessagingReaction),
+}
+
+impl MessagingType<'_> {
+    // Notifications about our own messages, they never enter the blocks
+    pub fn is_event(&self) -> bool {
+        match self {
+            MessagingType::DELIVERY(_) | MessagingType::READ(_)
+                | MessagingType::ECHO(_) | MessagingType::REACTION(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for MessagingType<'_> {
@@ -15,6 +60,11 @@ impl fmt::Display for MessagingType<'_> {
         match self {
             MessagingType::POSTBACK(_) => write!(f,"POSTBACK"),
             MessagingType::MESSAGE(_) => write!(f,"MESSAGE"),
+            MessagingType::ATTACHMENT(_) => write!(f,"ATTACHMENT"),
+            MessagingType::DELIVERY(_) => write!(f,"DELIVERY"),
+            MessagingType::READ(_) => write!(f,"READ"),
+            MessagingType::ECHO(_) => write!(f,"ECHO"),
+            MessagingType::REACTION(_) => write!(f,"REACTION"),
         }
     }
 }
@@ -24,6 +74,11 @@ pub enum PipeStatus {
     NEXT,
     REPLAY,
     RESTART,
+    WAIT,
+    // Jump to a PipeBox of another block
+    GOTO(String,usize),
+    // Consume this PipeBox again after a typing delay or a retry backoff
+    DELAY(Duration),
 }
 
 impl fmt::Display for PipeStatus {
@@ -31,7 +86,10 @@ impl fmt::Display for PipeStatus {
         match self {
             PipeStatus::NEXT => write!(f,"NEXT Status"),
             PipeStatus::REPLAY => write!(f,"REPLAY Satus"),
-            PipeStatus::RESTART => write!(f,"NEXT Status"),
+            PipeStatus::RESTART => write!(f,"RESTART Status"),
+            PipeStatus::WAIT => write!(f,"WAIT Status"),
+            PipeStatus::GOTO(block,index) => write!(f,"GOTO {}:{} Status",block,index),
+            PipeStatus::DELAY(delay) => write!(f,"DELAY {:?} Status",delay),
         }
     }
 }
@@ -50,8 +108,13 @@ impl fmt::Display for dyn Messaging{
 }
 
 pub trait PipeBox {
-    fn consume(&self,message: &BotUser, token: &str) -> PipeStatus;
+    fn consume(&self,message: &BotUser, client: &Client, session: &mut Session) -> PipeStatus;
     fn internal_state(&self) -> &PipeStatus;
+
+    // What the box sends and where it leads, unknown by default
+    fn outline(&self) -> Outline {
+        Outline::default()
+    }
 }
 
 #[derive(Clone)]
@@ -62,12 +125,22 @@ pub struct Conf {
     workers: u16,
     token_webhook: String,
     token_fb_page: String,
+    app_secret: String,
+    allow_unsigned: bool,
+    retry: RetryPolicy,
+    graph_url: String,
+    graph_version: String,
+    template_fallback: String,
+    intent_threshold: f64,
+    session_ttl: Option<Duration>,
+    welcome_back: Option<String>,
+    window_policy: WindowPolicy,
 }
 
 impl fmt::Display for Conf {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Configuration : \nPort: {}\nIp: {}\nURI: {}\nWorkers: {}\nToken webhook: {}\nToken FB: {}"
-            , self.port, self.ip, self.uri, self.workers, self.token_webhook, self.token_fb_page)
+        write!(f, "Configuration : \nPort: {}\nIp: {}\nURI: {}\nWorkers: {}\nToken webhook: {}\nToken FB: {}\nGraph: {}/{}"
+            , self.port, self.ip, self.uri, self.workers, self.token_webhook, self.token_fb_page, self.graph_url, self.graph_version)
     }
 }
 
@@ -81,6 +154,16 @@ impl Conf {
             workers: size,
             token_webhook: String::from(token_webhook),
             token_fb_page: String::from(token_fb_page),
+            app_secret: String::new(),
+            allow_unsigned: false,
+            retry: RetryPolicy::default(),
+            graph_url: String::from("https://graph.facebook.com"),
+            graph_version: String::from("v9.0"),
+            template_fallback: String::new(),
+            intent_threshold: 0.5,
+            session_ttl: None,
+            welcome_back: None,
+            window_policy: WindowPolicy::REFUSE,
         }
     }
 
@@ -93,6 +176,55 @@ impl Conf {
         self.token_fb_page = String::from(token);
     }
 
+    // Secret of the Facebook app, used to check the webhook signatures
+    pub fn set_app_secret(&mut self, secret: &str) {
+        self.app_secret = String::from(secret);
+    }
+
+    // Accept webhook POSTs without a secret to check them, only for local tests
+    pub fn set_allow_unsigned(&mut self, allow: bool) {
+        self.allow_unsigned = allow;
+    }
+
+    // Retry of the transient Send API failures
+    pub fn set_retry(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    // Host of the Graph API, a local mock server in the tests
+    pub fn set_graph_url(&mut self, url: &str) {
+        self.graph_url = String::from(url.trim_end_matches('/'));
+    }
+
+    pub fn set_graph_version(&mut self, version: &str) {
+        self.graph_version = String::from(version);
+    }
+
+    // Text of a template variable found nowhere
+    pub fn set_template_fallback(&mut self, fallback: &str) {
+        self.template_fallback = String::from(fallback);
+    }
+
+    // Confidence under which an intent doesn't start its block
+    pub fn set_intent_threshold(&mut self, threshold: f64) {
+        self.intent_threshold = threshold;
+    }
+
+    // Time a user may stay silent before their session expires
+    pub fn set_session_ttl(&mut self, ttl: Duration) {
+        self.session_ttl = Some(ttl);
+    }
+
+    // Block started by the next message of a user whose session expired
+    pub fn set_welcome_back(&mut self, block: &str) {
+        self.welcome_back = Some(String::from(block));
+    }
+
+    // What to do with a message out of the 24h window
+    pub fn set_window_policy(&mut self, policy: WindowPolicy) {
+        self.window_policy = policy;
+    }
+
     // set Vars conf
     pub fn set_port(&mut self, port: u16) {
         self.port = port;
@@ -133,6 +265,46 @@ impl Conf {
     pub fn get_token_fb_page(&self) -> &str {
         &self.token_fb_page
     }
+
+    pub fn get_app_secret(&self) -> &str {
+        &self.app_secret
+    }
+
+    pub fn get_allow_unsigned(&self) -> bool {
+        self.allow_unsigned
+    }
+
+    pub fn get_retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn get_graph_url(&self) -> &str {
+        &self.graph_url
+    }
+
+    pub fn get_graph_version(&self) -> &str {
+        &self.graph_version
+    }
+
+    pub fn get_template_fallback(&self) -> &str {
+        &self.template_fallback
+    }
+
+    pub fn get_intent_threshold(&self) -> f64 {
+        self.intent_threshold
+    }
+
+    pub fn get_session_ttl(&self) -> Option<Duration> {
+        self.session_ttl
+    }
+
+    pub fn get_welcome_back(&self) -> Option<&str> {
+        self.welcome_back.as_deref()
+    }
+
+    pub fn get_window_policy(&self) -> &WindowPolicy {
+        &self.window_policy
+    }
 }
 
 impl Default for Conf {
@@ -144,6 +316,16 @@ impl Default for Conf {
             workers: 12,
             token_webhook: String::from("MamaGuriba"),
             token_fb_page: String::from("MamaGuriba"),
+            app_secret: String::new(),
+            allow_unsigned: false,
+            retry: RetryPolicy::default(),
+            graph_url: String::from("https://graph.facebook.com"),
+            graph_version: String::from("v9.0"),
+            template_fallback: String::new(),
+            intent_threshold: 0.5,
+            session_ttl: None,
+            welcome_back: None,
+            window_policy: WindowPolicy::REFUSE,
         }
     }
 }
@@ -152,9 +334,46 @@ impl Default for Conf {
 pub struct BotUser {
     sender_id: String,
     message: Arc<dyn Messaging + Send + Sync>,
+    vars: HashMap<String,String>,
+    // Unix time of their last message, None when unknown
+    received: Option<u64>,
 }
 
-impl<'de> Deserialize<'de> for BotUser {
+// One messaging event of a webhook POST
+#[derive(Clone)]
+pub struct WebhookEvent {
+    page_id: String,
+    timestamp: u64,
+    user: BotUser,
+}
+
+impl WebhookEvent {
+    pub fn get_page_id(&self) -> &str {
+        &self.page_id
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn get_user(&self) -> &BotUser {
+        &self.user
+    }
+}
+
+// Every messaging event of every entry of a webhook POST, in order
+#[derive(Clone)]
+pub struct Webhook {
+    events: Vec<WebhookEvent>,
+}
+
+impl Webhook {
+    pub fn get_events(&self) -> &[WebhookEvent] {
+        &self.events
+    }
+}
+
+impl<'de> Deserialize<'de> for Webhook {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
@@ -162,35 +381,105 @@ impl<'de> Deserialize<'de> for BotUser {
 
         let json: Value =  Value::deserialize(deserializer)?;
 
-        println!("{}",json);
+        debug!("Webhook batch {}", json);
 
-        let object: &String = match &json["object"] {
-            Value::String(e) if e == "page" => e,
+        match &json["object"] {
+            Value::String(e) if e == "page" => {},
             _ => return Err(de::Error::custom("Doesn't have a valid json format API FB")),
         };
 
-        let id = match &json["entry"][0]["messaging"][0]["sender"]["id"] {
+        let entries = match &json["entry"] {
+            Value::Array(e) => e,
+            _ => return Err(de::Error::custom("Doesn't have an entry array in json")),
+        };
+
+        let mut events = Vec::new();
+        for entry in entries {
+            let page_id = entry["id"].as_str().unwrap_or_default();
+
+            let messaging = match &entry["messaging"] {
+                Value::Array(e) => e,
+                _ => {
+                    warn!("Entry of page {} doesn't have messaging events", page_id);
+                    continue
+                }
+            };
+
+            for value in messaging {
+                // A bad event is skipped so it doesn't drop the rest of the batch
+                match BotUser::deserialize(value) {
+                    Ok(user) => {
+                        // Messenger timestamps are in milliseconds
+                        let timestamp = value["timestamp"].as_u64();
+                        events.push(WebhookEvent{
+                            page_id: String::from(page_id),
+                            timestamp: timestamp.unwrap_or_default(),
+                            user: match timestamp {
+                                Some(e) => user.with_received(e / 1000),
+                                None => user,
+                            },
+                        })
+                    },
+                    Err(e) => warn!("Skip messaging event: {}", e),
+                }
+            }
+        }
+
+        Ok(Webhook{events: events})
+    }
+}
+
+// Parse one messaging event of the webhook
+impl<'de> Deserialize<'de> for BotUser {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+
+        let json: Value =  Value::deserialize(deserializer)?;
+
+        let id = match &json["sender"]["id"] {
             Value::String(e) => e,
             _ => return Err(de::Error::custom("Doesn't have a sender id in json")),
         };
 
-        let messageP: Option<MessagingPostback> = match &json["entry"][0]["messaging"][0]["postback"]["payload"] {
+        if let Some(i) = MessagingDelivery::from_json(&json) {
+            return Ok(BotUser::new(&id, Arc::new(i)));
+        }
+        else if let Some(i) = MessagingRead::from_json(&json) {
+            return Ok(BotUser::new(&id, Arc::new(i)));
+        }
+        else if let Some(i) = MessagingEcho::from_json(&json) {
+            return Ok(BotUser::new(&id, Arc::new(i)));
+        }
+        else if let Some(i) = MessagingReaction::from_json(&json) {
+            return Ok(BotUser::new(&id, Arc::new(i)));
+        }
+
+        let messageP: Option<MessagingPostback> = match &json["postback"]["payload"] {
             Value::String(e) => {
                 Some(MessagingPostback{payload: e.clone()})
             },
             _ => None,
         };
 
-        let messageQ: Option<MessagingPostback> = match &json["entry"][0]["messaging"][0]["message"]["quick_reply"]["payload"] {
+        let messageQ: Option<MessagingPostback> = match &json["message"]["quick_reply"]["payload"] {
             Value::String(e) => {
                 Some(MessagingPostback{payload: e.clone()})
             },
             _ => None,
         };
         
-        let messageM: Option<MessagingMessage> = match &json["entry"][0]["messaging"][0]["message"]["text"] {
+        let messageM: Option<MessagingMessage> = match &json["message"]["text"] {
             Value::String(e) => {
-                Some(MessagingMessage{text: e.clone()})
+                Some(MessagingMessage{text: e.clone(), nlp: Nlp::from_json(&json["message"]["nlp"])})
+            },
+            _ => None,
+        };
+
+        let messageA: Option<MessagingAttachment> = match &json["message"]["attachments"] {
+            Value::Array(e) if !e.is_empty() => {
+                Some(MessagingAttachment{attachments: e.iter().map(Attachment::from_json).collect()})
             },
             _ => None,
         };
@@ -205,6 +494,9 @@ impl<'de> Deserialize<'de> for BotUser {
             if let Some(i) = messageM {
                 return Ok(BotUser::new(&id, Arc::new(i)));
             }
+            else if let Some(i) = messageA {
+                return Ok(BotUser::new(&id, Arc::new(i)));
+            }
             else{
                 return Err(de::Error::custom("Don't have Messaging or Postback value in json"));
             }
@@ -230,6 +522,38 @@ impl BotUser {
         BotUser{
             sender_id: String::from(id),
             message: message,
+            vars: HashMap::new(),
+            received: None,
+        }
+    }
+
+    // Same user carrying the variables of their session
+    pub fn with_vars(&self, vars: &HashMap<String,String>) -> Self {
+        let mut user = self.clone();
+        user.vars = vars.clone();
+        user
+    }
+
+    pub fn with_received(mut self, received: u64) -> Self {
+        self.received = Some(received);
+        self
+    }
+
+    pub fn get_received(&self) -> Option<u64> {
+        self.received
+    }
+
+    // Value captured earlier in the session
+    pub fn get_var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|x| x.as_str())
+    }
+
+    // Confidence of a built-in NLP entity of a text message, like
+    // user.nlp_confidence("wit$greetings") > 0.8 in a control function
+    pub fn nlp_confidence(&self, name: &str) -> f64 {
+        match self.message.message_type() {
+            MessagingType::MESSAGE(e) => e.get_nlp().confidence(name),
+            _ => 0.0,
         }
     }
 
@@ -265,6 +589,26 @@ impl<'a> Messaging for MessagingPostback {
 #[derive(Clone)]
 pub struct MessagingMessage {
     text: String,
+    nlp: Nlp,
+}
+
+impl MessagingMessage {
+    pub fn new(text: &str) -> Self {
+        MessagingMessage{
+            text: String::from(text),
+            nlp: Nlp::default(),
+        }
+    }
+
+    pub fn with_nlp(mut self, nlp: Nlp) -> Self {
+        self.nlp = nlp;
+        self
+    }
+
+    // Entities of the built-in NLP, empty when it's off for the page
+    pub fn get_nlp(&self) -> &Nlp {
+        &self.nlp
+    }
 }
 
 impl<'a> Messaging for MessagingMessage {
@@ -274,4 +618,348 @@ impl<'a> Messaging for MessagingMessage {
     fn message(&self) -> &str {
         &self.text
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone,PartialEq)]
+pub enum AttachmentType {
+    IMAGE,
+    AUDIO,
+    VIDEO,
+    FILE,
+    LOCATION,
+    STICKER,
+    FALLBACK,
+}
+
+impl AttachmentType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttachmentType::IMAGE => "image",
+            AttachmentType::AUDIO => "audio",
+            AttachmentType::VIDEO => "video",
+            AttachmentType::FILE => "file",
+            AttachmentType::LOCATION => "location",
+            AttachmentType::STICKER => "sticker",
+            AttachmentType::FALLBACK => "fallback",
+        }
+    }
+}
+
+impl fmt::Display for AttachmentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"{}",self.as_str())
+    }
+}
+
+#[derive(Clone)]
+pub struct Attachment {
+    kind: AttachmentType,
+    url: Option<String>,
+    sticker_id: Option<u64>,
+    coordinates: Option<(f64,f64)>,
+}
+
+impl Attachment {
+    // Parse one item of message.attachments
+    fn from_json(json: &Value) -> Self {
+        let payload = &json["payload"];
+        let sticker_id = payload["sticker_id"].as_u64();
+
+        // Stickers are sent as images with a sticker id
+        let kind = match json["type"].as_str() {
+            Some("image") if sticker_id.is_some() => AttachmentType::STICKER,
+            Some("image") => AttachmentType::IMAGE,
+            Some("audio") => AttachmentType::AUDIO,
+            Some("video") => AttachmentType::VIDEO,
+            Some("file") => AttachmentType::FILE,
+            Some("location") => AttachmentType::LOCATION,
+            _ => AttachmentType::FALLBACK,
+        };
+
+        let coordinates = match (payload["coordinates"]["lat"].as_f64(), payload["coordinates"]["long"].as_f64()) {
+            (Some(lat), Some(long)) => Some((lat,long)),
+            _ => None,
+        };
+
+        Attachment{
+            kind: kind,
+            url: payload["url"].as_str().map(String::from),
+            sticker_id: sticker_id,
+            coordinates: coordinates,
+        }
+    }
+
+    pub fn get_kind(&self) -> &AttachmentType {
+        &self.kind
+    }
+
+    pub fn get_url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn get_sticker_id(&self) -> Option<u64> {
+        self.sticker_id
+    }
+
+    // Latitude and longitude of a shared location
+    pub fn get_coordinates(&self) -> Option<(f64,f64)> {
+        self.coordinates
+    }
+}
+
+#[derive(Clone)]
+pub struct MessagingAttachment {
+    attachments: Vec<Attachment>,
+}
+
+impl MessagingAttachment {
+    pub fn get_attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+
+    pub fn has(&self, kind: AttachmentType) -> bool {
+        self.attachments.iter().any(|x| x.kind == kind)
+    }
+}
+
+// No text, a block catches attachments with a Trigger::ATTACHMENT
+impl<'a> Messaging for MessagingAttachment {
+    fn message_type(&self) -> MessagingType {
+        MessagingType::ATTACHMENT(&self)
+    }
+    fn message(&self) -> &str {
+        ""
+    }
+}
+
+#[derive(Clone)]
+pub struct MessagingDelivery {
+    mids: Vec<String>,
+    watermark: u64,
+}
+
+impl MessagingDelivery {
+    fn from_json(json: &Value) -> Option<Self> {
+        let delivery = json.get("delivery")?;
+        Some(MessagingDelivery{
+            mids: delivery["mids"].as_array()
+                .map(|x| x.iter().filter_map(|e| e.as_str().map(String::from)).collect())
+                .unwrap_or_default(),
+            watermark: delivery["watermark"].as_u64().unwrap_or_default(),
+        })
+    }
+
+    pub fn get_mids(&self) -> &[String] {
+        &self.mids
+    }
+
+    // Every message sent before this timestamp was delivered
+    pub fn get_watermark(&self) -> u64 {
+        self.watermark
+    }
+}
+
+impl<'a> Messaging for MessagingDelivery {
+    fn message_type(&self) -> MessagingType {
+        MessagingType::DELIVERY(&self)
+    }
+    fn message(&self) -> &str {
+        ""
+    }
+}
+
+#[derive(Clone)]
+pub struct MessagingRead {
+    watermark: u64,
+}
+
+impl MessagingRead {
+    fn from_json(json: &Value) -> Option<Self> {
+        let read = json.get("read")?;
+        Some(MessagingRead{
+            watermark: read["watermark"].as_u64().unwrap_or_default(),
+        })
+    }
+
+    // Every message sent before this timestamp was read
+    pub fn get_watermark(&self) -> u64 {
+        self.watermark
+    }
+}
+
+impl<'a> Messaging for MessagingRead {
+    fn message_type(&self) -> MessagingType {
+        MessagingType::READ(&self)
+    }
+    fn message(&self) -> &str {
+        ""
+    }
+}
+
+// Copy of a message sent by the page, the sender is the page
+#[derive(Clone)]
+pub struct MessagingEcho {
+    mid: String,
+    recipient_id: String,
+    text: String,
+}
+
+impl MessagingEcho {
+    fn from_json(json: &Value) -> Option<Self> {
+        match json["message"]["is_echo"] {
+            Value::Bool(true) => Some(MessagingEcho{
+                mid: json["message"]["mid"].as_str().unwrap_or_default().to_string(),
+                recipient_id: json["recipient"]["id"].as_str().unwrap_or_default().to_string(),
+                text: json["message"]["text"].as_str().unwrap_or_default().to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn get_mid(&self) -> &str {
+        &self.mid
+    }
+
+    pub fn get_recipient(&self) -> &str {
+        &self.recipient_id
+    }
+}
+
+impl<'a> Messaging for MessagingEcho {
+    fn message_type(&self) -> MessagingType {
+        MessagingType::ECHO(&self)
+    }
+    fn message(&self) -> &str {
+        &self.text
+    }
+}
+
+#[derive(Clone)]
+pub struct MessagingReaction {
+    mid: String,
+    action: String,
+    reaction: String,
+    emoji: String,
+}
+
+impl MessagingReaction {
+    fn from_json(json: &Value) -> Option<Self> {
+        let reaction = json.get("reaction")?;
+        Some(MessagingReaction{
+            mid: reaction["mid"].as_str().unwrap_or_default().to_string(),
+            action: reaction["action"].as_str().unwrap_or_default().to_string(),
+            reaction: reaction["reaction"].as_str().unwrap_or_default().to_string(),
+            emoji: reaction["emoji"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    // Message the user reacted to
+    pub fn get_mid(&self) -> &str {
+        &self.mid
+    }
+
+    // "react" or "unreact"
+    pub fn get_action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn get_emoji(&self) -> &str {
+        &self.emoji
+    }
+}
+
+impl<'a> Messaging for MessagingReaction {
+    fn message_type(&self) -> MessagingType {
+        MessagingType::REACTION(&self)
+    }
+    fn message(&self) -> &str {
+        &self.reaction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Webhook, MessagingType, AttachmentType};
+    use super::trigger::Trigger;
+
+    #[test]
+    fn webhook_batch() {
+        let body = r##"{"object":"page","entry":[
+            {"id":"42","time":1,"messaging":[
+                {"sender":{"id":"1"},"recipient":{"id":"42"},"timestamp":10,"message":{"mid":"m1","text":"Hello"}},
+                {"sender":{"id":"2"},"recipient":{"id":"42"},"timestamp":11,"postback":{"payload":"#Start"}}
+            ]},
+            {"id":"42","time":2,"messaging":[
+                {"sender":{"id":"3"},"recipient":{"id":"42"},"timestamp":12,"message":{"mid":"m2","text":"Bye","quick_reply":{"payload":"Hello"}}},
+                {"sender":{"id":"4"},"recipient":{"id":"42"},"timestamp":13,"unknown":{}}
+            ]}
+        ]}"##;
+
+        let webhook: Webhook = serde_json::from_str(body).unwrap();
+        let events = webhook.get_events();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].get_user().get_sender(), "1");
+        assert_eq!(events[0].get_user().get_message().message(), "Hello");
+        assert_eq!(events[1].get_timestamp(), 11);
+        assert!(matches!(events[1].get_user().get_message().message_type(), MessagingType::POSTBACK(_)));
+        assert_eq!(events[2].get_user().get_message().message(), "Hello");
+        assert_eq!(events[2].get_page_id(), "42");
+    }
+
+    #[test]
+    fn webhook_attachments() {
+        let body = r#"{"object":"page","entry":[{"id":"42","time":1,"messaging":[
+            {"sender":{"id":"1"},"timestamp":10,"message":{"mid":"m1","attachments":[
+                {"type":"image","payload":{"url":"https://x/sticker.png","sticker_id":369239263222822}}]}},
+            {"sender":{"id":"1"},"timestamp":11,"message":{"mid":"m2","attachments":[
+                {"type":"location","payload":{"coordinates":{"lat":47.39,"long":0.68}}}]}}
+        ]}]}"#;
+
+        let webhook: Webhook = serde_json::from_str(body).unwrap();
+        let events = webhook.get_events();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].get_user().get_message().message(), "");
+        assert!(Trigger::ATTACHMENT(AttachmentType::STICKER).matches(events[0].get_user()).is_some());
+        assert!(Trigger::ATTACHMENT(AttachmentType::IMAGE).matches(events[0].get_user()).is_none());
+        match events[1].get_user().get_message().message_type() {
+            MessagingType::ATTACHMENT(a) => {
+                assert!(a.has(AttachmentType::LOCATION));
+                assert_eq!(a.get_attachments()[0].get_coordinates(), Some((47.39,0.68)));
+            },
+            _ => panic!("Expected an attachment"),
+        }
+    }
+
+    #[test]
+    fn webhook_notifications() {
+        let body = r#"{"object":"page","entry":[{"id":"42","time":1,"messaging":[
+            {"sender":{"id":"1"},"timestamp":10,"delivery":{"mids":["m1","m2"],"watermark":9}},
+            {"sender":{"id":"1"},"timestamp":11,"read":{"watermark":10}},
+            {"sender":{"id":"42"},"recipient":{"id":"1"},"timestamp":12,"message":{"is_echo":true,"mid":"m3","text":"Hello"}},
+            {"sender":{"id":"1"},"timestamp":13,"reaction":{"mid":"m3","action":"react","reaction":"love","emoji":"❤"}}
+        ]}]}"#;
+
+        let webhook: Webhook = serde_json::from_str(body).unwrap();
+        let events = webhook.get_events();
+
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|x| x.get_user().get_message().message_type().is_event()));
+        match events[0].get_user().get_message().message_type() {
+            MessagingType::DELIVERY(d) => assert_eq!(d.get_mids().len(), 2),
+            _ => panic!("Expected a delivery"),
+        }
+        match events[2].get_user().get_message().message_type() {
+            MessagingType::ECHO(e) => assert_eq!(e.get_recipient(), "1"),
+            _ => panic!("Expected an echo"),
+        }
+        assert_eq!(events[3].get_user().get_message().message(), "love");
+    }
+
+    #[test]
+    fn webhook_not_page() {
+        assert!(serde_json::from_str::<Webhook>(r#"{"object":"user","entry":[]}"#).is_err());
+    }
+}
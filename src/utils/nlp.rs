@@ -0,0 +1,132 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+// One value found by the built-in NLP of the page
+#[derive(Clone)]
+pub struct NlpEntity {
+    value: String,
+    confidence: f64,
+    body: Option<String>,
+}
+
+impl NlpEntity {
+    pub fn new(value: &str, confidence: f64) -> Self {
+        NlpEntity{
+            value: String::from(value),
+            confidence: confidence,
+            body: None,
+        }
+    }
+
+    pub fn get_value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn get_confidence(&self) -> f64 {
+        self.confidence
+    }
+
+    // Part of the text the value comes from, like "tomorrow" for a datetime
+    pub fn get_body(&self) -> Option<&str> {
+        self.body.as_deref()
+    }
+
+    fn from_json(json: &Value) -> Option<Self> {
+        let value = match &json["value"] {
+            Value::String(e) => e.clone(),
+            Value::Null => return None,
+            e => e.to_string(),
+        };
+
+        Some(NlpEntity{
+            value: value,
+            confidence: json["confidence"].as_f64().unwrap_or(0.0),
+            body: json["body"].as_str().map(String::from),
+        })
+    }
+}
+
+// message.nlp of a text message, the entities and traits by name like
+// "wit$greetings" or "wit$datetime:datetime"
+#[derive(Clone, Default)]
+pub struct Nlp {
+    entities: HashMap<String,Vec<NlpEntity>>,
+}
+
+impl Nlp {
+    pub fn from_json(json: &Value) -> Self {
+        let mut entities = HashMap::new();
+
+        for group in &[&json["entities"], &json["traits"]] {
+            if let Value::Object(e) = group {
+                for (name, values) in e {
+                    let values: Vec<NlpEntity> = values.as_array()
+                        .map(|x| x.iter().filter_map(NlpEntity::from_json).collect())
+                        .unwrap_or_default();
+                    entities.insert(name.clone(), values);
+                }
+            }
+        }
+
+        Nlp{
+            entities: entities,
+        }
+    }
+
+    pub fn entity(mut self, name: &str, value: NlpEntity) -> Self {
+        self.entities.entry(String::from(name)).or_insert_with(Vec::new).push(value);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn get_entities(&self) -> &HashMap<String,Vec<NlpEntity>> {
+        &self.entities
+    }
+
+    // Most confident value, "wit$datetime" finds "wit$datetime:datetime"
+    pub fn get(&self, name: &str) -> Option<&NlpEntity> {
+        self.entities.iter()
+            .filter(|(k,_)| k.as_str() == name || k.split(':').next() == Some(name))
+            .flat_map(|(_,v)| v.iter())
+            .fold(None, |best: Option<&NlpEntity>, x| match best {
+                Some(b) if b.confidence >= x.confidence => Some(b),
+                _ => Some(x),
+            })
+    }
+
+    // Confidence of the entity, 0 when missing or when its value is "false"
+    // like a greetings trait sure there is no greeting
+    pub fn confidence(&self, name: &str) -> f64 {
+        match self.get(name) {
+            Some(e) if e.value != "false" => e.confidence,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Nlp;
+
+    #[test]
+    fn parse_nlp() {
+        let nlp = Nlp::from_json(&json!({
+            "entities": {
+                "wit$datetime:datetime": [{"confidence": 0.97, "value": "2021-01-02T00:00:00.000-08:00", "body": "tomorrow"}]
+            },
+            "traits": {
+                "wit$greetings": [{"id": "5900cc2d", "value": "true", "confidence": 0.99}],
+                "wit$bye": [{"id": "5900cc2e", "value": "false", "confidence": 0.95}]
+            }
+        }));
+
+        assert_eq!(nlp.get("wit$datetime").unwrap().get_body(), Some("tomorrow"));
+        assert!(nlp.confidence("wit$greetings") > 0.8);
+        assert_eq!(nlp.confidence("wit$bye"), 0.0);
+        assert_eq!(nlp.confidence("wit$sentiment"), 0.0);
+    }
+}
@@ -0,0 +1,414 @@
+use serde_derive::{Serialize, Deserialize};
+use super::write_atomic;
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Position of a user in the flows and the values kept for them
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    sender_id: String,
+    block: Option<String>,
+    index: usize,
+    vars: HashMap<String,String>,
+    // Unix time of the last message, sessions saved before it start now
+    #[serde(default = "now")]
+    updated: u64,
+    // Left its block on timeout, the next message gets the welcome back block
+    #[serde(default)]
+    expired: bool,
+    // Unix time of the last message of the user, opening the 24h window,
+    // sessions saved before it are taken as out of the window
+    #[serde(default)]
+    received: u64,
+    #[serde(default)]
+    follow_ups: Vec<FollowUp>,
+    // Unix time in milliseconds when the PipeBox on a typing delay or a
+    // retry backoff goes on
+    #[serde(default)]
+    resume: Option<u64>,
+    // Failed tries of the send of the current PipeBox
+    #[serde(default)]
+    attempts: u32,
+}
+
+// Text sent later to the user, kept in the session to survive restarts
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FollowUp {
+    due: u64,
+    text: String,
+    cancel_on_reply: bool,
+    // PipeBox that scheduled it, like "Cart[0]"
+    #[serde(default)]
+    key: String,
+}
+
+impl FollowUp {
+    pub fn new(delay: Duration, text: &str, cancel_on_reply: bool) -> Self {
+        FollowUp{
+            due: now() + delay.as_secs(),
+            text: String::from(text),
+            cancel_on_reply: cancel_on_reply,
+            key: String::new(),
+        }
+    }
+
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.key = String::from(key);
+        self
+    }
+
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    // Unix time from when it's sent
+    pub fn get_due(&self) -> u64 {
+        self.due
+    }
+
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_cancel_on_reply(&self) -> bool {
+        self.cancel_on_reply
+    }
+}
+
+// Unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+// Unix time in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0)
+}
+
+impl Session {
+    pub fn new(sender_id: &str) -> Self {
+        Session{
+            sender_id: String::from(sender_id),
+            updated: now(),
+            received: now(),
+            ..Session::default()
+        }
+    }
+
+    pub fn get_sender(&self) -> &str {
+        &self.sender_id
+    }
+
+    // Block the user is in, None once they finished their last block
+    pub fn get_block(&self) -> Option<&str> {
+        self.block.as_deref()
+    }
+
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    pub fn set_index(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    // Put the user on a PipeBox of a block
+    pub fn enter(&mut self, block: &str, index: usize) {
+        self.block = Some(String::from(block));
+        self.index = index;
+        self.clear_pause();
+    }
+
+    // The user is out of any block, their variables are kept
+    pub fn leave(&mut self) {
+        self.block = None;
+        self.index = 0;
+        self.clear_pause();
+    }
+
+    // The current PipeBox goes on after the delay
+    pub fn pause(&mut self, delay: Duration) {
+        self.resume = Some(now_millis() + delay.as_millis() as u64);
+    }
+
+    pub fn clear_pause(&mut self) {
+        self.resume = None;
+        self.attempts = 0;
+    }
+
+    // Unix time in milliseconds when the current PipeBox goes on
+    pub fn get_resume(&self) -> Option<u64> {
+        self.resume
+    }
+
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn set_attempts(&mut self, attempts: u32) {
+        self.attempts = attempts;
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|x| x.as_str())
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(String::from(name), String::from(value));
+    }
+
+    pub fn get_vars(&self) -> &HashMap<String,String> {
+        &self.vars
+    }
+
+    // The user just wrote
+    pub fn touch(&mut self) {
+        self.updated = now();
+        self.received = self.updated;
+        self.expired = false;
+    }
+
+    pub fn get_received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn set_received(&mut self, received: u64) {
+        self.received = received;
+    }
+
+    pub fn schedule(&mut self, follow_up: FollowUp) {
+        self.follow_ups.push(follow_up);
+    }
+
+    pub fn get_follow_ups(&self) -> &[FollowUp] {
+        &self.follow_ups
+    }
+
+    // Drop the follow ups still pending from a PipeBox, it schedules them again
+    pub fn remove_follow_ups(&mut self, key: &str) -> usize {
+        let before = self.follow_ups.len();
+        self.follow_ups.retain(|x| x.key != key);
+        before - self.follow_ups.len()
+    }
+
+    // The user replied first, drop what was waiting for their answer
+    pub fn cancel_follow_ups(&mut self) -> usize {
+        let before = self.follow_ups.len();
+        self.follow_ups.retain(|x| !x.cancel_on_reply);
+        before - self.follow_ups.len()
+    }
+
+    // Remove and give back the follow ups due at this time
+    pub fn take_due(&mut self, at: u64) -> Vec<FollowUp> {
+        let (due, waiting) = self.follow_ups.drain(..).partition(|x| x.due <= at);
+        self.follow_ups = waiting;
+        due
+    }
+
+    pub fn get_updated(&self) -> u64 {
+        self.updated
+    }
+
+    pub fn set_updated(&mut self, updated: u64) {
+        self.updated = updated;
+    }
+
+    // Silent for longer than the ttl
+    pub fn is_stale(&self, ttl: Duration) -> bool {
+        now().saturating_sub(self.updated) > ttl.as_secs()
+    }
+
+    // Out of its block after a timeout
+    pub fn expire(&mut self) {
+        self.leave();
+        self.updated = now();
+        self.expired = true;
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+}
+
+// Where the sessions live between two messages
+pub trait SessionStore: Send + Sync {
+    fn get(&self, sender_id: &str) -> Option<Session>;
+    fn set(&self, session: Session);
+    fn remove(&self, sender_id: &str);
+    fn all(&self) -> Vec<Session>;
+}
+
+// Sessions lost on restart
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String,Session>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn get(&self, sender_id: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().get(sender_id).cloned()
+    }
+
+    fn set(&self, session: Session) {
+        self.sessions.lock().unwrap().insert(session.sender_id.clone(), session);
+    }
+
+    fn remove(&self, sender_id: &str) {
+        self.sessions.lock().unwrap().remove(sender_id);
+    }
+
+    fn all(&self) -> Vec<Session> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+}
+
+// How long a change waits before the file of a FileStore is rewritten
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sessions kept in a json file, rewritten at most once per SAVE_INTERVAL by
+// a thread of its own and when the store is dropped, a crash loses the
+// changes of the last interval
+pub struct FileStore {
+    inner: Arc<FileInner>,
+}
+
+struct FileInner {
+    path: PathBuf,
+    sessions: Mutex<HashMap<String,Session>>,
+    dirty: AtomicBool,
+    // Only one write of the file at a time
+    saving: Mutex<()>,
+}
+
+impl FileStore {
+    // Open the file, a missing file starts an empty store
+    pub fn new(path: &str) -> io::Result<Self> {
+        let sessions = match fs::read_to_string(path) {
+            Ok(e) => serde_json::from_str(&e)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        let inner = Arc::new(FileInner{
+            path: PathBuf::from(path),
+            sessions: Mutex::new(sessions),
+            dirty: AtomicBool::new(false),
+            saving: Mutex::new(()),
+        });
+
+        // Stops with the store
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || loop {
+            thread::sleep(SAVE_INTERVAL);
+            match weak.upgrade() {
+                Some(e) => e.flush(),
+                None => break,
+            }
+        });
+
+        Ok(FileStore{inner: inner})
+    }
+
+    // Write the pending changes now
+    pub fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+impl FileInner {
+    fn flush(&self) {
+        let _saving = self.saving.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return
+        }
+
+        // Serialized under the lock, written out of it
+        let json = serde_json::to_string(&*self.sessions.lock().unwrap());
+
+        let result = json
+            .map_err(io::Error::from)
+            .and_then(|json| write_atomic(&self.path, json.as_bytes()));
+
+        if let Err(e) = result {
+            warn!("Sessions not saved in {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl SessionStore for FileStore {
+    fn get(&self, sender_id: &str) -> Option<Session> {
+        self.inner.sessions.lock().unwrap().get(sender_id).cloned()
+    }
+
+    fn set(&self, session: Session) {
+        self.inner.sessions.lock().unwrap().insert(session.sender_id.clone(), session);
+        self.inner.dirty.store(true, Ordering::SeqCst);
+    }
+
+    fn remove(&self, sender_id: &str) {
+        if self.inner.sessions.lock().unwrap().remove(sender_id).is_some() {
+            self.inner.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn all(&self) -> Vec<Session> {
+        self.inner.sessions.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Session, SessionStore, FileStore};
+    use std::fs;
+
+    #[test]
+    fn file_store_survives_restart() {
+        let path = std::env::temp_dir().join(format!("botMessenger-sessions-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let store = FileStore::new(path).unwrap();
+        let mut session = Session::new("1");
+        session.enter("Hello", 2);
+        session.set_var("email", "bear@forest.fr");
+        store.set(session);
+        drop(store);
+
+        let store = FileStore::new(path).unwrap();
+        let session = store.get("1").unwrap();
+        assert_eq!(session.get_block(), Some("Hello"));
+        assert_eq!(session.get_index(), 2);
+        assert_eq!(session.get_var("email"), Some("bear@forest.fr"));
+
+        // The changes wait for the next save or a flush
+        store.remove("1");
+        store.flush();
+        assert!(FileStore::new(path).unwrap().get("1").is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn legacy_session_out_of_window() {
+        let session: Session = serde_json::from_str(r#"{"sender_id":"1","block":null,"index":0,"vars":{}}"#).unwrap();
+        assert_eq!(session.get_received(), 0);
+    }
+}
@@ -0,0 +1,67 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use log::warn;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Check the X-Hub-Signature-256 header ("sha256=<hex>") against the raw body
+pub fn verify_signature(secret: &str, body: &[u8], header: &str) -> bool {
+    let signature = match header.strip_prefix("sha256=") {
+        Some(e) => e,
+        None => {
+            warn!("Signature header doesn't start with sha256=");
+            return false
+        }
+    };
+
+    let signature = match hex::decode(signature) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Signature header isn't valid hex: {}", e);
+            return false
+        }
+    };
+
+    let mut mac = match HmacSha256::new_varkey(secret.as_bytes()) {
+        Ok(e) => e,
+        Err(_) => return false,
+    };
+    mac.update(body);
+
+    // verify compare in constant time
+    mac.verify(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::verify_signature;
+
+    const BODY: &str = r#"{"object":"page","entry":[]}"#;
+
+    fn sign(secret: &str, body: &str) -> String {
+        use hmac::{Mac, NewMac};
+        let mut mac = super::HmacSha256::new_varkey(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn valid_signature() {
+        let header = sign("MamaGuriba", BODY);
+        assert!(verify_signature("MamaGuriba", BODY.as_bytes(), &header));
+    }
+
+    #[test]
+    fn wrong_secret_or_body() {
+        let header = sign("MamaGuriba", BODY);
+        assert!(!verify_signature("Other", BODY.as_bytes(), &header));
+        assert!(!verify_signature("MamaGuriba", b"{}", &header));
+    }
+
+    #[test]
+    fn malformed_header() {
+        assert!(!verify_signature("MamaGuriba", BODY.as_bytes(), "sha1=abcd"));
+        assert!(!verify_signature("MamaGuriba", BODY.as_bytes(), "sha256=zz"));
+    }
+}
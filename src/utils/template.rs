@@ -0,0 +1,69 @@
+use once_cell::sync::Lazy;
+use regex::{Regex, Captures};
+use serde_json::Value;
+
+// {{name}} or {{name|fallback}}
+static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\{\s*([A-Za-z0-9_.\-]+)\s*(?:\|([^}]*))?\}\}").unwrap());
+
+// Replace every placeholder with the value given by lookup, else its own
+// fallback, else the global one
+pub fn render<F>(text: &str, lookup: &F, fallback: &str) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if !text.contains("{{") {
+        return String::from(text)
+    }
+
+    PLACEHOLDER.replace_all(text, |caps: &Captures| {
+        match lookup(&caps[1]) {
+            Some(e) => e,
+            None => caps.get(2).map(|x| x.as_str().trim().to_string()).unwrap_or_else(|| String::from(fallback)),
+        }
+    }).into_owned()
+}
+
+// Render every string of a json, like the texts, titles, urls and payloads of a message
+pub fn render_json<F>(value: &mut Value, lookup: &F, fallback: &str)
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        Value::String(e) => *e = render(e, lookup, fallback),
+        Value::Array(e) => e.iter_mut().for_each(|x| render_json(x, lookup, fallback)),
+        Value::Object(e) => e.iter_mut().for_each(|x| render_json(x.1, lookup, fallback)),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{render, render_json};
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "first_name" => Some(String::from("Loic")),
+            "order_id" => Some(String::from("42")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn render_text() {
+        assert_eq!(render("Hi {{first_name}}, your order {{ order_id }} ships today", &lookup, ""),
+            "Hi Loic, your order 42 ships today");
+        assert_eq!(render("Hi {{nickname|friend}} {{city}}!", &lookup, "?"), "Hi friend ?!");
+    }
+
+    #[test]
+    fn render_message() {
+        let mut json = json!({"attachment": {"payload": {"elements": [
+            {"title": "Order {{order_id}}", "image_url": "https://x/{{order_id}}.png",
+                "buttons": [{"type": "postback", "title": "Track", "payload": "TRACK_{{order_id}}"}]}
+        ]}}});
+        render_json(&mut json, &lookup, "");
+        assert_eq!(json["attachment"]["payload"]["elements"][0]["buttons"][0]["payload"], "TRACK_42");
+        assert_eq!(json["attachment"]["payload"]["elements"][0]["image_url"], "https://x/42.png");
+    }
+}
@@ -0,0 +1,155 @@
+use super::{BotUser, MessagingType, AttachmentType};
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+use std::collections::HashMap;
+use std::fmt;
+
+// Way a message starts a block, when several blocks match the one with the
+// strongest trigger wins: name, EXACT or ATTACHMENT, NOCASE, NORMALIZED, REGEX,
+// NLP then KEYWORDS, and between equal triggers the first block added
+#[derive(Clone)]
+pub enum Trigger {
+    EXACT(String),
+    NOCASE(String),
+    // Case, accents and punctuation ignored, "Hello!" and "hèllo" match "hello"
+    NORMALIZED(String),
+    // Named groups like (?P<city>\w+) are kept as session variables
+    REGEX(Regex),
+    // Built-in NLP entity over a confidence, like NLP("wit$greetings", 0.8),
+    // its value is kept as a session variable named like "greetings"
+    NLP(String,f64),
+    // One of the words in the normalized message
+    KEYWORDS(Vec<String>),
+    // Attachment of this type sent by the user, like a photo or a location
+    ATTACHMENT(AttachmentType),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::EXACT(e) => write!(f,"{}",e),
+            Trigger::NOCASE(e) => write!(f,"nocase {}",e),
+            Trigger::NORMALIZED(e) => write!(f,"normalized {}",e),
+            Trigger::REGEX(e) => write!(f,"/{}/",e),
+            Trigger::NLP(name,threshold) => write!(f,"{} > {}",name,threshold),
+            Trigger::KEYWORDS(e) => write!(f,"keywords {}",e.join(", ")),
+            Trigger::ATTACHMENT(e) => write!(f,"attachment {}",e),
+        }
+    }
+}
+
+impl Trigger {
+    pub fn keywords(words: &[&str]) -> Self {
+        Trigger::KEYWORDS(words.iter().map(|x| normalize(x)).collect())
+    }
+
+    // Lower is stronger, 0 is kept for the name of the block
+    pub fn priority(&self) -> usize {
+        match self {
+            Trigger::EXACT(_) => 1,
+            Trigger::NOCASE(_) => 2,
+            Trigger::NORMALIZED(_) => 3,
+            Trigger::REGEX(_) => 4,
+            Trigger::NLP(_,_) => 5,
+            Trigger::KEYWORDS(_) => 6,
+            // Never competes with the text triggers
+            Trigger::ATTACHMENT(_) => 1,
+        }
+    }
+
+    // Captured variables when the message matches
+    pub fn matches(&self, user: &BotUser) -> Option<HashMap<String,String>> {
+        let message = user.get_message();
+        let text = message.message();
+        let found = match self {
+            Trigger::EXACT(e) => text == e,
+            Trigger::NOCASE(e) => text.to_lowercase() == e.to_lowercase(),
+            Trigger::NORMALIZED(e) => normalize(text) == normalize(e),
+            Trigger::REGEX(e) => {
+                let caps = e.captures(text)?;
+                return Some(e.capture_names()
+                    .flatten()
+                    .filter_map(|x| caps.name(x).map(|v| (String::from(x), String::from(v.as_str()))))
+                    .collect())
+            },
+            Trigger::NLP(name, threshold) => {
+                if user.nlp_confidence(name) <= *threshold {
+                    return None
+                }
+
+                let mut vars = HashMap::new();
+                if let MessagingType::MESSAGE(m) = message.message_type() {
+                    if let Some(entity) = m.get_nlp().get(name) {
+                        let var = name.rsplit('$').next().unwrap_or(name).split(':').next().unwrap_or(name);
+                        vars.insert(String::from(var), String::from(entity.get_value()));
+                    }
+                }
+                return Some(vars)
+            },
+            Trigger::KEYWORDS(e) => {
+                let text = normalize(text);
+                text.split(' ').any(|x| e.iter().any(|k| k == x))
+            },
+            Trigger::ATTACHMENT(e) => match message.message_type() {
+                MessagingType::ATTACHMENT(a) => a.has(e.clone()),
+                _ => false,
+            },
+        };
+
+        match found {
+            true => Some(HashMap::new()),
+            false => None,
+        }
+    }
+}
+
+// Lowercase without accents and punctuation, words split by one space
+pub fn normalize(text: &str) -> String {
+    let text: String = text.nfd()
+        .filter(|x| !is_combining_mark(*x))
+        .map(|x| if x.is_alphanumeric() { x } else { ' ' })
+        .collect::<String>()
+        .to_lowercase();
+
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Trigger, normalize};
+    use crate::api::transport::user;
+    use crate::utils::{BotUser, MessagingMessage, AttachmentType};
+    use crate::utils::nlp::{Nlp, NlpEntity};
+    use regex::Regex;
+    use std::sync::Arc;
+
+    #[test]
+    fn triggers() {
+        assert_eq!(normalize("  Hèllo, WORLD!! "), "hello world");
+        assert!(Trigger::NOCASE(String::from("Hello")).matches(&user("hello")).is_some());
+        assert!(Trigger::NORMALIZED(String::from("hello")).matches(&user("Héllo !")).is_some());
+        assert!(Trigger::keywords(&["hi", "hello"]).matches(&user("Hi there")).is_some());
+        assert!(Trigger::keywords(&["hi"]).matches(&user("high")).is_none());
+        assert!(Trigger::ATTACHMENT(AttachmentType::IMAGE).matches(&user("image")).is_none());
+
+        let regex = Trigger::REGEX(Regex::new(r"weather in (?P<city>\w+)").unwrap());
+        let vars = regex.matches(&user("What's the weather in Tours ?")).unwrap();
+        assert_eq!(vars.get("city").map(|x| x.as_str()), Some("Tours"));
+        assert!(regex.matches(&user("hello")).is_none());
+    }
+
+    #[test]
+    fn nlp_trigger() {
+        let nlp = Nlp::default()
+            .entity("wit$greetings", NlpEntity::new("true", 0.92))
+            .entity("wit$datetime:datetime", NlpEntity::new("2021-01-02", 0.6));
+        let user = BotUser::new("1", Arc::new(MessagingMessage::new("hey").with_nlp(nlp)));
+
+        assert!(Trigger::NLP(String::from("wit$greetings"), 0.8).matches(&user).is_some());
+        assert!(Trigger::NLP(String::from("wit$datetime"), 0.8).matches(&user).is_none());
+        let vars = Trigger::NLP(String::from("wit$datetime"), 0.5).matches(&user).unwrap();
+        assert_eq!(vars.get("datetime").map(|x| x.as_str()), Some("2021-01-02"));
+    }
+}
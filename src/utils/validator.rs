@@ -0,0 +1,82 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static EMAIL: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").unwrap());
+static PHONE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\+?[0-9 .\-()]+$").unwrap());
+static DATE_ISO: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(\d{4})-(\d{1,2})-(\d{1,2})$").unwrap());
+static DATE_DAY_FIRST: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(\d{1,2})[/.\-](\d{1,2})[/.\-](\d{4})$").unwrap());
+
+// Check applied to an answer before it is kept in the session
+#[derive(Clone)]
+pub enum Validator {
+    REGEX(Regex),
+    NUMBER,
+    EMAIL,
+    PHONE,
+    DATE,
+}
+
+impl Validator {
+    pub fn is_valid(&self, text: &str) -> bool {
+        let text = text.trim();
+        match self {
+            Validator::REGEX(e) => e.is_match(text),
+            Validator::NUMBER => text.replace(',', ".").parse::<f64>().is_ok(),
+            Validator::EMAIL => EMAIL.is_match(text),
+            Validator::PHONE => {
+                let digits = text.chars().filter(|x| x.is_ascii_digit()).count();
+                PHONE.is_match(text) && digits >= 6 && digits <= 15
+            },
+            Validator::DATE => is_date(text),
+        }
+    }
+}
+
+// 2020-12-31, 31/12/2020, 31-12-2020 or 31.12.2020
+fn is_date(text: &str) -> bool {
+    let (year, month, day) = if let Some(e) = DATE_ISO.captures(text) {
+        (e[1].parse::<u32>(), e[2].parse::<u32>(), e[3].parse::<u32>())
+    }
+    else if let Some(e) = DATE_DAY_FIRST.captures(text) {
+        (e[3].parse::<u32>(), e[2].parse::<u32>(), e[1].parse::<u32>())
+    }
+    else {
+        return false
+    };
+
+    match (year, month, day) {
+        (Ok(year), Ok(month), Ok(day)) => {
+            let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+            let days = match month {
+                1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+                4 | 6 | 9 | 11 => 30,
+                2 if leap => 29,
+                2 => 28,
+                _ => return false,
+            };
+            day >= 1 && day <= days
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Validator;
+    use regex::Regex;
+
+    #[test]
+    fn validators() {
+        assert!(Validator::NUMBER.is_valid(" 12,5 "));
+        assert!(!Validator::NUMBER.is_valid("twelve"));
+        assert!(Validator::EMAIL.is_valid("bear@forest.fr"));
+        assert!(!Validator::EMAIL.is_valid("bear@forest"));
+        assert!(Validator::PHONE.is_valid("+33 6 12 34 56 78"));
+        assert!(!Validator::PHONE.is_valid("12"));
+        assert!(Validator::DATE.is_valid("29/02/2020"));
+        assert!(Validator::DATE.is_valid("2021-12-31"));
+        assert!(!Validator::DATE.is_valid("29/02/2021"));
+        assert!(Validator::REGEX(Regex::new(r"^[A-Z]{2}\d{4}$").unwrap()).is_valid("AB1234"));
+    }
+}